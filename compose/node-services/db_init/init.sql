@@ -1,10 +1,14 @@
+-- 服务启动时也会执行这个文件，所有语句都必须是幂等的
 CREATE TABLE IF NOT EXISTS accounts (
                 address varchar(255) NOT NULL,
                 created_at varchar(255) NOT NULL,
+                timezone varchar(64) NOT NULL DEFAULT 'UTC',
                 app_id_index int NOT NULL,
                 PRIMARY KEY (address)
             );
 
+ALTER TABLE accounts ADD COLUMN IF NOT EXISTS timezone varchar(64) NOT NULL DEFAULT 'UTC';
+
 CREATE TABLE IF NOT EXISTS apps (
                 account varchar(50) NOT NULL,
                 id int NOT NULL,
@@ -16,5 +20,81 @@ CREATE TABLE IF NOT EXISTS apps (
                 created_at varchar(255) NOT NULL,
                 http_link varchar(100) NOT NULL,
                 websocket_link varchar(100) NOT NULL,
+                tier varchar(20) NOT NULL DEFAULT 'full',
                 PRIMARY KEY (account, id)
-            );
\ No newline at end of file
+            );
+
+ALTER TABLE apps ADD COLUMN IF NOT EXISTS tier varchar(20) NOT NULL DEFAULT 'full';
+
+CREATE TABLE IF NOT EXISTS usage_minute (
+                api_key varchar(50) NOT NULL,
+                chain varchar(50) NOT NULL,
+                status_class varchar(10) NOT NULL,
+                minute timestamptz NOT NULL,
+                requests bigint NOT NULL,
+                compute_units bigint NOT NULL DEFAULT 0,
+                cache_hits bigint NOT NULL DEFAULT 0,
+                PRIMARY KEY (api_key, chain, status_class, minute)
+            );
+
+CREATE INDEX IF NOT EXISTS usage_minute_minute_idx ON usage_minute (minute);
+
+CREATE TABLE IF NOT EXISTS usage_daily (
+                api_key varchar(50) NOT NULL,
+                chain varchar(50) NOT NULL,
+                status_class varchar(10) NOT NULL,
+                day date NOT NULL,
+                requests bigint NOT NULL,
+                compute_units bigint NOT NULL DEFAULT 0,
+                cache_hits bigint NOT NULL DEFAULT 0,
+                PRIMARY KEY (api_key, chain, status_class, day)
+            );
+
+ALTER TABLE usage_minute ADD COLUMN IF NOT EXISTS compute_units bigint NOT NULL DEFAULT 0;
+ALTER TABLE usage_daily ADD COLUMN IF NOT EXISTS compute_units bigint NOT NULL DEFAULT 0;
+ALTER TABLE usage_minute ADD COLUMN IF NOT EXISTS cache_hits bigint NOT NULL DEFAULT 0;
+ALTER TABLE usage_daily ADD COLUMN IF NOT EXISTS cache_hits bigint NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS usage_checkpoint (
+                source varchar(255) NOT NULL,
+                msec double precision NOT NULL,
+                first_msec double precision NOT NULL,
+                PRIMARY KEY (source)
+            );
+
+-- 以前的检查点没有起点，按从头开始处理
+ALTER TABLE usage_checkpoint ADD COLUMN IF NOT EXISTS first_msec double precision NOT NULL DEFAULT 0;
+
+-- 补录写入的时间范围，每次补录一个文件一行，范围之间可以有空隙
+CREATE TABLE IF NOT EXISTS usage_backfill (
+                source varchar(255) NOT NULL,
+                first_msec double precision NOT NULL,
+                msec double precision NOT NULL,
+                loaded_at timestamptz NOT NULL DEFAULT now(),
+                PRIMARY KEY (source, first_msec, msec)
+            );
+
+CREATE TABLE IF NOT EXISTS credit_topups (
+                id bigserial NOT NULL,
+                account varchar(255) NOT NULL,
+                amount bigint NOT NULL,
+                note varchar(255) NOT NULL DEFAULT '',
+                created_at timestamptz NOT NULL DEFAULT now(),
+                PRIMARY KEY (id)
+            );
+
+CREATE INDEX IF NOT EXISTS credit_topups_account_idx ON credit_topups (account, created_at);
+
+CREATE TABLE IF NOT EXISTS credit_debits (
+                account varchar(255) NOT NULL,
+                api_key varchar(50) NOT NULL,
+                chain varchar(50) NOT NULL,
+                hour timestamptz NOT NULL,
+                compute_units bigint NOT NULL,
+                PRIMARY KEY (api_key, chain, hour)
+            );
+
+CREATE INDEX IF NOT EXISTS credit_debits_account_idx ON credit_debits (account, hour);
+
+-- balances are derived from credit_topups and credit_debits, the old running total is gone
+DROP TABLE IF EXISTS credit_balances;
@@ -0,0 +1,27 @@
+// json-rpc method names of the request body, comma separated, batches keep their order.
+// only the names are logged so params (addresses, signed transactions) never reach the log
+function methods(r) {
+    var body = r.requestText;
+    if (!body) {
+        return '';
+    }
+    var calls;
+    try {
+        calls = JSON.parse(body);
+    } catch (e) {
+        return '';
+    }
+    if (!Array.isArray(calls)) {
+        calls = [calls];
+    }
+    return calls
+        .filter(function (call) {
+            return call && typeof call.method === 'string';
+        })
+        .map(function (call) {
+            return call.method.replace(/,/g, '');
+        })
+        .join(',');
+}
+
+export default { methods };
@@ -1,17 +1,31 @@
-use std::net::SocketAddr;
+use std::{convert::Infallible, net::SocketAddr};
 
 use axum::{
     extract::{Path, Query},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get, post},
+    http::{header, Request, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post, put},
     Json, Router,
 };
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 
-use crate::model::{
-    account::Account,
-    chain::{Chain, ChainEnum, NetworkEnum},
+use crate::{
+    model::{
+        account::Account,
+        anomaly,
+        app::App,
+        chain::{network_enums, ChainEnum, Network},
+        health,
+        log_parse::{cache::LogCache, live},
+        registry::{self, Tier},
+    },
+    proxy,
 };
 
 fn get_listen_port() -> u16 {
@@ -24,19 +38,61 @@ pub async fn serve() {
     println!("listening on {}", addr);
     tracing::info!("listening on {}", addr);
 
+    // 管理接口需要管理员令牌
+    let admin = Router::new()
+        .route("/admin/anomalies", get(anomalies))
+        .route("/admin/accounts/:account/credits", post(top_up))
+        .route_layer(middleware::from_fn(require_admin));
     let app = Router::new()
         .route("/chains", get(chains))
         .route("/networks/:chain", get(networks))
+        .route("/networks/:chain/details", get(network_details))
         .route("/apps/:account", get(get_apps))
+        .route("/account/:account/timezone", put(set_timezone))
+        .route("/account/:account/statements/:month", get(statement))
         .route("/app", post(create_app))
-        .route("/app/:account/:app_id", delete(delete_app));
+        .route("/app/:account/:app_id", delete(delete_app))
+        .route("/app/:account/:app_id/live", get(live_app))
+        .route("/health", get(health))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .merge(admin)
+        .fallback(proxy::handle);
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 }
 
+/// 检查 `Authorization: Bearer <ADMIN_TOKEN>`，没有配置 ADMIN_TOKEN 时拒绝所有管理请求
+async fn require_admin<B>(request: Request<B>, next: Next<B>) -> axum::response::Response {
+    let token = std::env::var("ADMIN_TOKEN").unwrap_or_default();
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if token.is_empty() || !same(token.as_bytes(), given.as_bytes()) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(Response::new(
+                "unauthorized".to_string(),
+                serde_json::Value::Null,
+                None,
+            )),
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// 比较令牌，耗时和不同的位置无关
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct Response {
     message: String,
@@ -86,10 +142,32 @@ pub async fn chains() -> impl IntoResponse {
 }
 
 pub async fn networks(Path(chain): Path<String>) -> impl IntoResponse {
-    let Ok(chain) = chain.parse::<ChainEnum>() else {
+    let Some(chain) = registry::get().chain(&chain) else {
         return (StatusCode::BAD_REQUEST, Json(Response::new("chain invalid".to_string(), serde_json::Value::Null, None)));
     };
-    match serde_json::to_value(Chain::new(chain).networks) {
+    match serde_json::to_value(network_enums(chain)) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
+/// 链的每个网络的地址、状态和钱包配置，`/networks/:chain` 保持原来的枚举格式
+pub async fn network_details(Path(chain): Path<String>) -> impl IntoResponse {
+    let Some(chain) = registry::get().chain(&chain) else {
+        return (StatusCode::BAD_REQUEST, Json(Response::new("chain invalid".to_string(), serde_json::Value::Null, None)));
+    };
+    let networks: Vec<Network> = chain
+        .networks
+        .iter()
+        .map(|n| Network::new(chain, n))
+        .collect();
+    match serde_json::to_value(networks) {
         Ok(result) => (
             StatusCode::OK,
             Json(Response::new("ok".to_string(), result, None)),
@@ -108,6 +186,9 @@ pub struct CreateApp {
     pub chain: String,
     pub network: String,
     pub account: String,
+    /// 不传时是全节点
+    #[serde(default)]
+    pub tier: Tier,
 }
 
 pub async fn create_app(Json(payload): Json<CreateApp>) -> impl IntoResponse {
@@ -120,14 +201,20 @@ pub async fn create_app(Json(payload): Json<CreateApp>) -> impl IntoResponse {
             )
         }
     };
-    let Ok(chain) = payload.chain.parse::<ChainEnum>() else {
+    let Some(chain) = registry::get().chain(&payload.chain) else {
         return (StatusCode::BAD_REQUEST, Json(Response::new("chain invaild".to_string(), serde_json::Value::Null, None)));
     };
-    let Ok(network) = payload.network.parse::<NetworkEnum>() else {
+    let Some(network) = chain.network(&payload.network) else {
         return (StatusCode::BAD_REQUEST, Json(Response::new("network invaild".to_string(), serde_json::Value::Null, None)));
     };
     let app = match user
-        .create_app(&payload.name, &payload.description, chain, network)
+        .create_app(
+            &payload.name,
+            &payload.description,
+            chain,
+            &network.name,
+            payload.tier,
+        )
         .await
     {
         Ok(app) => app,
@@ -154,17 +241,42 @@ pub async fn create_app(Json(payload): Json<CreateApp>) -> impl IntoResponse {
     }
 }
 
+/// 用量按哪个时区的自然日统计，例如 `?tz=Asia/Shanghai`，不传时使用账户设置的时区
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TimezoneQuery {
+    pub tz: Option<String>,
+}
+
+fn parse_timezone(tz: &str) -> Option<Tz> {
+    tz.parse().ok()
+}
+
 pub async fn get_apps(
     Path(account): Path<String>,
     Query(pagination): Query<Pagination>,
+    Query(timezone): Query<TimezoneQuery>,
 ) -> impl IntoResponse {
     let Ok(user) = Account::get(&account).await else {
         return (StatusCode::BAD_REQUEST, Json(Response::new("account invalid".to_string(), serde_json::Value::Null, None)));
     };
+    let tz = match timezone.tz.as_deref().map(parse_timezone) {
+        None => None,
+        Some(Some(tz)) => Some(tz),
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(Response::new(
+                    "tz invalid".to_string(),
+                    serde_json::Value::Null,
+                    None,
+                )),
+            )
+        }
+    };
     let size = pagination.size.unwrap_or(10);
     let page = pagination.page.unwrap_or(1);
 
-    let apps = match user.get_apps(page, size).await {
+    let apps = match user.get_apps(page, size, tz).await {
         Ok(apps) => apps,
         Err(e) => {
             return (
@@ -198,6 +310,111 @@ pub async fn get_apps(
     }
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SetTimezone {
+    pub timezone: String,
+}
+
+pub async fn set_timezone(
+    Path(account): Path<String>,
+    Json(payload): Json<SetTimezone>,
+) -> impl IntoResponse {
+    let Some(tz) = parse_timezone(&payload.timezone) else {
+        return (StatusCode::BAD_REQUEST, Json(Response::new("timezone invalid".to_string(), serde_json::Value::Null, None)));
+    };
+    let Ok(mut user) = Account::get(&account).await else {
+        return (StatusCode::BAD_REQUEST, Json(Response::new("account invalid".to_string(), serde_json::Value::Null, None)));
+    };
+    if let Err(e) = user.set_timezone(tz).await {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        );
+    }
+    match serde_json::to_value(user) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct TopUp {
+    pub amount: i64,
+    #[serde(default)]
+    pub note: String,
+}
+
+/// 充值计算单位，返回充值后的账户
+pub async fn top_up(Path(account): Path<String>, Json(payload): Json<TopUp>) -> impl IntoResponse {
+    let mut user = match Account::find(&account).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(Response::new(
+                    "account not found".to_string(),
+                    serde_json::Value::Null,
+                    None,
+                )),
+            )
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+            )
+        }
+    };
+    if let Err(e) = user.top_up(payload.amount, &payload.note).await {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        );
+    }
+    match serde_json::to_value(user) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
+/// 月度账单，month 是 `2022-12` 这样的月份
+pub async fn statement(Path((account, month)): Path<(String, String)>) -> impl IntoResponse {
+    let Ok(user) = Account::get(&account).await else {
+        return (StatusCode::BAD_REQUEST, Json(Response::new("account invalid".to_string(), serde_json::Value::Null, None)));
+    };
+    let statement = match user.statement(&month).await {
+        Ok(statement) => statement,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+            )
+        }
+    };
+    match serde_json::to_value(statement) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
 pub async fn delete_app(Path((account, app_id)): Path<(String, String)>) -> impl IntoResponse {
     let Ok(user) = Account::get(&account).await else {
         return (StatusCode::BAD_REQUEST, Json(Response::new("account invalid".to_string(), serde_json::Value::Null, None)));
@@ -217,3 +434,80 @@ pub async fn delete_app(Path((account, app_id)): Path<(String, String)>) -> impl
         ),
     }
 }
+
+pub async fn live_app(
+    Path((account, app_id)): Path<(String, String)>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, Json<Response>)> {
+    let Ok(app_id) = app_id.parse::<i32>() else {
+        return Err((StatusCode::BAD_REQUEST, Json(Response::new("app id invalid".to_string(), serde_json::Value::Null, None))));
+    };
+    let app = match App::get(&account, app_id).await {
+        Ok(app) => app,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+            ))
+        }
+    };
+    let stream = ReceiverStream::new(live::subscribe(&app.api_key)).map(|stats| {
+        Ok(Event::default()
+            .event("usage")
+            .json_data(stats)
+            .unwrap_or_default())
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+pub async fn health() -> impl IntoResponse {
+    let health = LogCache::read(|cache| {
+        serde_json::json!({
+            "logs": cache.data.len(),
+            "latest_time": cache.latest_time,
+            "parse": cache.stats,
+        })
+    });
+    match health {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
+/// 每个节点的健康检查结果和最近的历史
+pub async fn status() -> impl IntoResponse {
+    match serde_json::to_value(health::status()) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
+
+/// Prometheus 格式的节点健康指标
+pub async fn metrics() -> String {
+    health::metrics()
+}
+
+/// 最近检测到的流量异常，新的在前
+pub async fn anomalies() -> impl IntoResponse {
+    match serde_json::to_value(anomaly::feed()) {
+        Ok(result) => (
+            StatusCode::OK,
+            Json(Response::new("ok".to_string(), result, None)),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(Response::new(e.to_string(), serde_json::Value::Null, None)),
+        ),
+    }
+}
@@ -1,7 +1,109 @@
-use node_service::{api, model::init};
+use chrono::NaiveDate;
+use node_service::{
+    api,
+    model::{
+        init,
+        log_parse::{format::LogFormat, source},
+        usage::{
+            backfill,
+            report::{Report, ReportFilter, ReportFormat},
+        },
+    },
+};
+
+const USAGE: &str = "usage:
+    node-service                     start the api server
+    node-service backfill <path> [--node <name>] [--format <format>]
+                                     load usage from rotated logs, <path> is a directory or a glob,
+                                     format is one of nginx_json, nginx_combined, caddy_json
+    node-service report --log <file> [--key <api_key>] [--from <yyyy-mm-dd>] [--to <yyyy-mm-dd>]
+                        [--format table|json|csv]
+                                     summarize usage from a json_analytics log without the database,
+                                     days are in UTC and both ends are inclusive";
 
 #[tokio::main]
 async fn main() {
-    init::init().await;
-    api::serve().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        None => {
+            init::init().await;
+            api::serve().await;
+        }
+        Some("backfill") => {
+            let Some(path) = args.get(1) else {
+                exit_with_usage();
+            };
+            let node = option(&args, "--node").unwrap_or(source::DEFAULT_NODE);
+            let format = match option(&args, "--format").map(|f| f.parse::<LogFormat>()) {
+                None => LogFormat::NginxJson,
+                Some(Ok(format)) => format,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    exit_with_usage();
+                }
+            };
+            init::init_command().await;
+            match backfill::run(path, node, format).await {
+                Ok(report) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&report).unwrap_or_default()
+                ),
+                Err(e) => {
+                    eprintln!("backfill failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("report") => {
+            let Some(path) = option(&args, "--log") else {
+                exit_with_usage();
+            };
+            let filter = ReportFilter {
+                api_key: option(&args, "--key").map(|k| k.to_string()),
+                from: date_option(&args, "--from"),
+                to: date_option(&args, "--to"),
+            };
+            let format = match option(&args, "--format").map(|f| f.parse::<ReportFormat>()) {
+                None => ReportFormat::Table,
+                Some(Ok(format)) => format,
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    exit_with_usage();
+                }
+            };
+            match Report::from_file(path, &filter) {
+                Ok(report) => print!("{}", report.render(format)),
+                Err(e) => {
+                    eprintln!("report failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(_) => exit_with_usage(),
+    }
+}
+
+/// 取 `--name value` 形式的参数
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|a| a == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|s| s.as_str())
+}
+
+/// 取 `--name yyyy-mm-dd` 形式的日期参数，格式不对时退出
+fn date_option(args: &[String], name: &str) -> Option<NaiveDate> {
+    let value = option(args, name)?;
+    match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => Some(date),
+        Err(e) => {
+            eprintln!("{} invalid: {}", name, e);
+            exit_with_usage();
+        }
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
 }
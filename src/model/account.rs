@@ -1,18 +1,24 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
 use chrono::prelude::*;
+use chrono_tz::Tz;
 
 use super::{
     app::{self, App},
-    chain::{ChainEnum, NetworkEnum},
-    db,
+    billing, db,
+    registry::{ChainConfig, Tier},
 };
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Account {
     pub address: String,
     pub created_at: String,
+    /// IANA 时区名，用量按这个时区的自然日统计
+    pub timezone: String,
+    /// 剩余的计算单位
+    #[serde(default)]
+    pub credit_balance: i64,
     app_id_index: i32,
 }
 
@@ -21,38 +27,51 @@ impl Account {
         let a = Self {
             address: address.to_string(),
             created_at: Local::now().to_string(),
+            timezone: Tz::UTC.to_string(),
+            credit_balance: 0,
             app_id_index: 0,
         };
         a.save().await?;
         Ok(a)
     }
 
+    /// 取账户，不存在时创建
     pub async fn get(address: &str) -> Result<Self> {
+        match Self::find(address).await? {
+            Some(user) => Ok(user),
+            None => Self::new(address).await,
+        }
+    }
+
+    /// 取已经存在的账户，不存在时返回 None
+    pub async fn find(address: &str) -> Result<Option<Self>> {
         let user = sqlx::query!(
-            "SELECT address, created_at, app_id_index FROM accounts WHERE address = $1",
+            "SELECT address, created_at, timezone, app_id_index FROM accounts WHERE address = $1",
             address
         )
-        .fetch_one(&db::get_pool()?)
-        .await
+        .fetch_optional(&db::get_pool()?)
+        .await?
         .map(|a| Self {
             address: a.address,
             created_at: a.created_at,
+            timezone: a.timezone,
+            credit_balance: 0,
             app_id_index: a.app_id_index,
-        })
-        .map_err(|e| anyhow!(e));
-        if user.is_ok() {
-            user
-        } else {
-            Self::new(address).await
-        }
+        });
+        let Some(mut user) = user else {
+            return Ok(None);
+        };
+        user.credit_balance = billing::balance(address).await?;
+        Ok(Some(user))
     }
 
     pub async fn create_app(
         &mut self,
         name: &str,
         description: &str,
-        chain: ChainEnum,
-        network: NetworkEnum,
+        chain: &ChainConfig,
+        network: &str,
+        tier: Tier,
     ) -> Result<App> {
         let app = App::new(
             &self.address,
@@ -61,6 +80,7 @@ impl Account {
             description,
             chain,
             network,
+            tier,
         )
         .await?;
         self.app_id_index += 1;
@@ -75,14 +95,15 @@ impl Account {
     async fn save(&self) -> Result<()> {
         sqlx::query!(
             "INSERT INTO accounts (
-                address, created_at, app_id_index
+                address, created_at, timezone, app_id_index
             ) VALUES (
-                $1, $2, $3
+                $1, $2, $3, $4
             )
             ON CONFLICT (address)
-            DO UPDATE SET app_id_index = $3;",
+            DO UPDATE SET timezone = $3, app_id_index = $4;",
             self.address,
             self.created_at,
+            self.timezone,
             self.app_id_index,
         )
         .execute(&db::get_pool()?)
@@ -94,7 +115,31 @@ impl Account {
         app::App::get_total(&self.address).await
     }
 
-    pub async fn get_apps(&self, page: i64, size: i64) -> Result<Vec<App>> {
-        app::App::get_with_page(&self.address, page, size).await
+    /// 没有指定时区时使用账户设置的时区
+    pub async fn get_apps(&self, page: i64, size: i64, tz: Option<Tz>) -> Result<Vec<App>> {
+        let tz = tz.unwrap_or_else(|| self.tz());
+        let mut apps = app::App::get_with_page(&self.address, page, size, tz).await?;
+        apps.iter_mut()
+            .for_each(|app| app.credit_balance = self.credit_balance);
+        Ok(apps)
+    }
+
+    /// 充值计算单位
+    pub async fn top_up(&mut self, amount: i64, note: &str) -> Result<()> {
+        self.credit_balance = billing::top_up(&self.address, amount, note).await?;
+        Ok(())
+    }
+
+    pub async fn statement(&self, month: &str) -> Result<billing::Statement> {
+        billing::statement(&self.address, month).await
+    }
+
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    pub async fn set_timezone(&mut self, tz: Tz) -> Result<()> {
+        self.timezone = tz.to_string();
+        self.save().await
     }
 }
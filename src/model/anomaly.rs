@@ -0,0 +1,443 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::RwLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    log_parse::{log::Log, route::Route},
+    usage::index::{self, UsageIndex, SLOTS_PER_DAY, SLOT_SECS},
+};
+
+static STATE: Lazy<RwLock<AnomalyState>> = Lazy::new(Default::default);
+/// 可以信任 X-Forwarded-For 的代理，例如 `172.16.0.0/12,10.0.0.5`
+static TRUSTED_PROXIES: Lazy<Vec<(IpAddr, u8)>> = Lazy::new(|| {
+    let value = std::env::var("TRUSTED_PROXIES").unwrap_or_default();
+    value
+        .split(',')
+        .filter(|v| !v.trim().is_empty())
+        .filter_map(|v| {
+            let network = parse_network(v);
+            if network.is_none() {
+                tracing::error!("trusted proxy invalid: {}", v);
+            }
+            network
+        })
+        .collect()
+});
+
+const CHECK_INTERVAL_SECS: u64 = 60;
+/// 用前 24 小时作为基线
+const BASELINE_SLOTS: i64 = SLOTS_PER_DAY;
+/// 一个时间段的请求超过基线平均值的倍数
+const SPIKE_FACTOR: f64 = 5.0;
+/// 一个时间段的请求少于这个数时不判断，避免小流量的 key 误报
+const MIN_REQUESTS: i64 = 300;
+/// 错误率至少达到这个值，并且是基线错误率的 ERROR_FACTOR 倍
+const MIN_ERROR_RATE: f64 = 0.2;
+const ERROR_FACTOR: f64 = 3.0;
+/// 新网段的请求占这个 key 当前请求的比例
+const NEW_RANGE_SHARE: f64 = 0.5;
+/// 一小时内第一次出现的网段算新网段
+const NEW_RANGE_SLOTS: i64 = 4;
+/// 一周没有出现的网段不再记住
+const RANGE_RETENTION_SLOTS: i64 = 7 * SLOTS_PER_DAY;
+const FEED_SIZE: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// 请求量相对基线突增
+    Spike,
+    /// 之前没见过的网段在大量请求
+    NewIpRange,
+    /// 错误率突增
+    ErrorSurge,
+}
+
+/// 某个 api key 在某个 15 分钟时间段内的异常
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Anomaly {
+    pub api_key: String,
+    pub kind: AnomalyKind,
+    pub slot: DateTime<Utc>,
+    /// 当前时间段的请求数、错误率或新网段的请求数
+    pub value: f64,
+    pub baseline: f64,
+    /// 新网段时是网段
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip_range: Option<String>,
+    pub throttled: bool,
+}
+
+/// 某个 api key 见过的客户端网段
+#[derive(Debug, Default)]
+struct IpRanges {
+    /// 网段 -> (第一次出现的时间段, 最后一次出现的时间段)
+    seen: HashMap<String, (i64, i64)>,
+    /// 最近几个时间段内每个网段的请求数
+    recent: BTreeMap<i64, HashMap<String, i64>>,
+    first_slot: i64,
+}
+
+#[derive(Debug, Default)]
+struct AnomalyState {
+    ranges: HashMap<String, IpRanges>,
+    flags: HashMap<String, Vec<Anomaly>>,
+    feed: VecDeque<Anomaly>,
+    throttled: HashSet<String>,
+}
+
+/// 启动后台检测任务，配置了 ANOMALY_THROTTLE=true 时 api 代理拒绝异常的 api key
+pub fn init() {
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(CHECK_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = check() {
+                tracing::error!("detect anomaly failed: {}", e);
+            }
+        }
+    });
+}
+
+/// 记录请求来自哪个网段，时间按日志的时间戳计算，重启后重新读取的日志也能恢复这些信息
+pub fn record(log: &Log) {
+    let (Some(route), Some(range)) = (Route::parse(&log.request_uri), ip_range(log)) else {
+        return;
+    };
+    let slot = log.msec.timestamp().div_euclid(SLOT_SECS);
+    let Ok(mut state) = STATE.write() else {
+        return;
+    };
+    let ranges = state
+        .ranges
+        .entry(route.api_key)
+        .or_insert_with(|| IpRanges {
+            first_slot: slot,
+            ..Default::default()
+        });
+    ranges.first_slot = ranges.first_slot.min(slot);
+    let seen = ranges.seen.entry(range.clone()).or_insert((slot, slot));
+    seen.0 = seen.0.min(slot);
+    seen.1 = seen.1.max(slot);
+    *ranges
+        .recent
+        .entry(slot)
+        .or_default()
+        .entry(range)
+        .or_insert(0) += 1;
+}
+
+/// 某个 api key 当前的异常
+pub fn flags(api_key: &str) -> Vec<Anomaly> {
+    STATE
+        .read()
+        .ok()
+        .and_then(|state| state.flags.get(api_key).cloned())
+        .unwrap_or_default()
+}
+
+/// 最近发现的异常，新的在前
+pub fn feed() -> Vec<Anomaly> {
+    STATE
+        .read()
+        .map(|state| state.feed.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// 这个 api key 是否因为异常被限流
+pub fn is_throttled(api_key: &str) -> bool {
+    STATE
+        .read()
+        .is_ok_and(|state| state.throttled.contains(api_key))
+}
+
+/// 检查最近一个完整的时间段
+fn check() -> Result<()> {
+    let Some(latest) = index::read(|index| index.latest_slot())? else {
+        return Ok(());
+    };
+    let slot = latest - 1;
+    let found = index::read(|index| {
+        let state = STATE.read().map_err(|e| anyhow!("{}", e))?;
+        Ok::<_, anyhow::Error>(detect(index, &state.ranges, slot))
+    })??;
+    let throttle = std::env::var("ANOMALY_THROTTLE").as_deref() == Ok("true");
+    let mut state = STATE.write().map_err(|e| anyhow!("{}", e))?;
+    let mut flags: HashMap<String, Vec<Anomaly>> = HashMap::new();
+    for mut anomaly in found {
+        // 错误率高通常是节点的问题，不限流
+        anomaly.throttled = throttle && anomaly.kind != AnomalyKind::ErrorSurge;
+        let known = state.flags.get(&anomaly.api_key).is_some_and(|flags| {
+            flags
+                .iter()
+                .any(|f| f.kind == anomaly.kind && f.ip_range == anomaly.ip_range)
+        });
+        if !known {
+            tracing::warn!("anomaly detected: {:?}", anomaly);
+            state.feed.push_front(anomaly.clone());
+            state.feed.truncate(FEED_SIZE);
+        }
+        flags
+            .entry(anomaly.api_key.clone())
+            .or_default()
+            .push(anomaly);
+    }
+    state.throttled = flags
+        .values()
+        .flatten()
+        .filter(|a| a.throttled)
+        .map(|a| a.api_key.clone())
+        .collect();
+    state.flags = flags;
+    state.evict(latest);
+    Ok(())
+}
+
+fn detect(index: &UsageIndex, ranges: &HashMap<String, IpRanges>, slot: i64) -> Vec<Anomaly> {
+    let slot_start = Utc
+        .timestamp_opt(slot * SLOT_SECS, 0)
+        .single()
+        .unwrap_or_default();
+    let mut found = Vec::new();
+    for api_key in index.active_keys(slot) {
+        let series = index.series(api_key, slot - BASELINE_SLOTS, slot + 1);
+        let Some((current, baseline)) = series.split_last() else {
+            continue;
+        };
+        if current.requests < MIN_REQUESTS {
+            continue;
+        }
+        let anomaly = |kind, value, baseline, ip_range| Anomaly {
+            api_key: api_key.to_string(),
+            kind,
+            slot: slot_start,
+            value,
+            baseline,
+            ip_range,
+            throttled: false,
+        };
+        let base_requests: i64 = baseline.iter().map(|c| c.requests).sum();
+        let base_errors: i64 = baseline.iter().map(|c| c.errors).sum();
+        let mean = base_requests as f64 / baseline.len().max(1) as f64;
+        if current.requests as f64 > SPIKE_FACTOR * mean.max(1.0) {
+            found.push(anomaly(
+                AnomalyKind::Spike,
+                current.requests as f64,
+                mean,
+                None,
+            ));
+        }
+        let rate = current.errors as f64 / current.requests as f64;
+        let base_rate = if base_requests > 0 {
+            base_errors as f64 / base_requests as f64
+        } else {
+            0.0
+        };
+        if rate >= MIN_ERROR_RATE && rate >= ERROR_FACTOR * base_rate {
+            found.push(anomaly(AnomalyKind::ErrorSurge, rate, base_rate, None));
+        }
+        // 没有一天以上历史的 key 所有网段都是新的，不判断
+        let Some(ranges) = ranges
+            .get(api_key)
+            .filter(|r| r.first_slot <= slot - BASELINE_SLOTS)
+        else {
+            continue;
+        };
+        for (range, count) in ranges.recent.get(&slot).into_iter().flatten() {
+            let is_new = ranges
+                .seen
+                .get(range)
+                .is_some_and(|(first, _)| *first > slot - NEW_RANGE_SLOTS);
+            if is_new
+                && *count >= MIN_REQUESTS
+                && *count as f64 >= NEW_RANGE_SHARE * current.requests as f64
+            {
+                found.push(anomaly(
+                    AnomalyKind::NewIpRange,
+                    *count as f64,
+                    0.0,
+                    Some(range.clone()),
+                ));
+            }
+        }
+    }
+    found
+}
+
+impl AnomalyState {
+    fn evict(&mut self, latest: i64) {
+        self.ranges.retain(|_, ranges| {
+            ranges.recent = ranges.recent.split_off(&(latest - NEW_RANGE_SLOTS));
+            ranges
+                .seen
+                .retain(|_, (_, last)| *last > latest - RANGE_RETENTION_SLOTS);
+            !ranges.seen.is_empty()
+        });
+    }
+}
+
+/// 客户端所在的网段，IPv4 取 /24，IPv6 取 /48
+fn ip_range(log: &Log) -> Option<String> {
+    match client_ip(log, &TRUSTED_PROXIES)? {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            Some(format!("{}.{}.{}.0/24", o[0], o[1], o[2]))
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            Some(format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2]))
+        }
+    }
+}
+
+/// X-Forwarded-For 可以由客户端伪造，只有请求来自可信的代理时才往前看一跳，
+/// 从右往左找到第一个不是可信代理的地址
+fn client_ip(log: &Log, trusted: &[(IpAddr, u8)]) -> Option<IpAddr> {
+    let mut ip: IpAddr = log.remote_addr.parse().ok()?;
+    let forwarded = log.http_x_forwarded_for.as_deref().unwrap_or_default();
+    for hop in forwarded.rsplit(',') {
+        if !trusted
+            .iter()
+            .any(|&(network, bits)| contains(network, bits, ip))
+        {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    Some(ip)
+}
+
+/// `10.0.0.0/8` 这样的网段，单个地址是全长的网段
+fn parse_network(s: &str) -> Option<(IpAddr, u8)> {
+    let (ip, bits) = s.trim().split_once('/').unwrap_or((s.trim(), ""));
+    let ip: IpAddr = ip.parse().ok()?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let bits = if bits.is_empty() {
+        max
+    } else {
+        bits.parse().ok()?
+    };
+    (bits <= max).then_some((ip, bits))
+}
+
+fn contains(network: IpAddr, bits: u8, ip: IpAddr) -> bool {
+    let (network, ip, len) = match (network, ip) {
+        (IpAddr::V4(n), IpAddr::V4(ip)) => (u32::from(n).into(), u32::from(ip).into(), 32),
+        (IpAddr::V6(n), IpAddr::V6(ip)) => (u128::from(n), u128::from(ip), 128),
+        _ => return false,
+    };
+    let shift = len - u32::from(bits);
+    network.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::usage::rollup::{Count, UsageKey};
+
+    fn add(index: &mut UsageIndex, slot: i64, status_class: &str, requests: i64) {
+        let key = UsageKey {
+            api_key: "0123abcd".to_string(),
+            chain: "Ethereum".to_string(),
+            status_class: status_class.to_string(),
+            minute: Utc.timestamp_opt(slot * SLOT_SECS, 0).unwrap(),
+        };
+        index.add(
+            &key,
+            Count {
+                requests,
+                compute_units: 0,
+                cache_hits: 0,
+            },
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        let slot = 1669862220 / SLOT_SECS;
+        let mut index = UsageIndex::default();
+        for i in 1..=BASELINE_SLOTS {
+            add(&mut index, slot - i, "2xx", 100);
+        }
+        add(&mut index, slot, "2xx", 100);
+        let ranges = HashMap::new();
+        assert!(detect(&index, &ranges, slot).is_empty());
+
+        add(&mut index, slot, "2xx", 500);
+        add(&mut index, slot, "5xx", 400);
+        let found = detect(&index, &ranges, slot);
+        let kinds: Vec<AnomalyKind> = found.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, vec![AnomalyKind::Spike, AnomalyKind::ErrorSurge]);
+        assert_eq!(found[0].value, 1000.0);
+        assert_eq!(found[0].baseline, 100.0);
+
+        let mut ip = IpRanges {
+            first_slot: slot - BASELINE_SLOTS,
+            ..Default::default()
+        };
+        ip.seen.insert("10.0.0.0/24".to_string(), (slot - 10, slot));
+        ip.seen.insert("10.0.1.0/24".to_string(), (slot, slot));
+        ip.recent.insert(
+            slot,
+            HashMap::from([
+                ("10.0.0.0/24".to_string(), 400),
+                ("10.0.1.0/24".to_string(), 600),
+            ]),
+        );
+        let ranges = HashMap::from([("0123abcd".to_string(), ip)]);
+        let found = detect(&index, &ranges, slot);
+        let new_range = found
+            .iter()
+            .find(|a| a.kind == AnomalyKind::NewIpRange)
+            .unwrap();
+        assert_eq!(new_range.ip_range.as_deref(), Some("10.0.1.0/24"));
+    }
+
+    #[test]
+    fn test_ip_range() {
+        let log = Log {
+            remote_addr: "172.23.0.1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(ip_range(&log).as_deref(), Some("172.23.0.0/24"));
+        let log = Log {
+            remote_addr: "172.23.0.1".to_string(),
+            http_x_forwarded_for: Some("2001:db8:1:2::1, 10.0.0.1".to_string()),
+            ..Default::default()
+        };
+        // 没有配置可信的代理时不看 X-Forwarded-For
+        assert_eq!(ip_range(&log).as_deref(), Some("172.23.0.0/24"));
+        let trusted = |networks: &[&str]| -> Vec<(IpAddr, u8)> {
+            networks.iter().filter_map(|n| parse_network(n)).collect()
+        };
+        assert_eq!(
+            client_ip(&log, &trusted(&["172.16.0.0/12"])),
+            "10.0.0.1".parse().ok()
+        );
+        assert_eq!(
+            client_ip(&log, &trusted(&["172.16.0.0/12", "10.0.0.1"])),
+            "2001:db8:1:2::1".parse().ok()
+        );
+        assert_eq!(
+            client_ip(&log, &trusted(&["172.24.0.0/16"])),
+            "172.23.0.1".parse().ok()
+        );
+        assert_eq!(parse_network("10.0.0.0/33"), None);
+        assert!(contains(
+            "::".parse().unwrap(),
+            0,
+            "2001:db8::1".parse().unwrap()
+        ));
+    }
+}
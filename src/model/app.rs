@@ -1,13 +1,30 @@
+use std::{collections::HashMap, sync::RwLock};
+
 use anyhow::{anyhow, Result};
-use chrono::Local;
+use chrono::{Local, Utc};
+use chrono_tz::Tz;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 use super::{
-    chain::{self, ChainEnum, NetworkEnum},
+    anomaly::{self, Anomaly},
+    chain::NetworkNotice,
     code_examples::examples,
     db, log_parse,
+    registry::{self, ChainConfig, NetworkConfig, NetworkStatus, Tier},
+    usage,
 };
 
+/// 所有应用的 api key，代理按它检查请求、选择节点池，计费按它选择价格
+static KEYS: Lazy<RwLock<HashMap<String, ApiKey>>> = Lazy::new(Default::default);
+
+/// api key 所属的账户和应用的节点类型
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiKey {
+    pub account: String,
+    pub tier: Tier,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct App {
     pub account: String,
@@ -16,6 +33,8 @@ pub struct App {
     pub description: String,
     pub chain: String,
     pub network: String,
+    /// 全节点或者归档节点
+    pub tier: Tier,
     pub api_key: String,
     pub created_at: String,
     pub http_link: String,
@@ -23,6 +42,17 @@ pub struct App {
     pub code_examples: examples::CodeExample,
     pub total_requests_today: i32,
     pub dayly_requests_7days: Vec<i32>,
+    /// 今天用这个 app 的 api key 访问其它链的请求数
+    pub chain_mismatch_requests_today: i32,
+    /// 今天用掉的计算单位
+    pub compute_units_today: i64,
+    /// 账户剩余的计算单位，所有应用共用
+    pub credit_balance: i64,
+    /// 最近检测到的流量异常
+    pub anomalies: Vec<Anomaly>,
+    /// 网络已经弃用或下线时提示迁移到哪个网络
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network_notice: Option<NetworkNotice>,
 }
 
 impl App {
@@ -31,42 +61,89 @@ impl App {
         id: i32,
         name: &str,
         description: &str,
-        chain: ChainEnum,
-        network: NetworkEnum,
+        chain: &ChainConfig,
+        network: &str,
+        tier: Tier,
     ) -> Result<Self> {
-        let ch = chain::Chain::new(chain.clone());
-        if !ch.have_network(&network.to_string()) {
+        let Some(network) = chain.network(network) else {
             return Err(anyhow!("Network not found"));
+        };
+        if network.status == NetworkStatus::Retired {
+            return Err(match &network.successor {
+                Some(successor) => {
+                    anyhow!("Network {} is retired, use {}", network.name, successor)
+                }
+                None => anyhow!("Network {} is retired", network.name),
+            });
+        }
+        if !network.is_available() {
+            return Err(anyhow!("Network {} has no endpoint", network.name));
+        }
+        if !network.tiers().contains(&tier) {
+            return Err(anyhow!("Tier {} is not offered on {}", tier, network.name));
         }
         let mut app = Self {
             account: account.to_string(),
             id,
             name: name.to_string(),
             description: description.to_string(),
-            chain: chain.to_string(),
-            network: network.to_string(),
+            chain: chain.name.clone(),
+            network: network.name.clone(),
+            tier,
             created_at: Local::now().to_string(),
             ..Default::default()
         };
-        app.generate_key()?;
+        app.generate_key(network);
         app.save().await?;
-        app.generate_code_example(chain);
+        set_key(&app.api_key, &app.account, tier);
+        app.generate_code_example();
+        app.network_notice = NetworkNotice::of(&app.chain, &app.network);
         Ok(app)
     }
 
     pub async fn delete(account: &str, id: i32) -> Result<()> {
-        let n = sqlx::query!(
-            "DELETE FROM apps WHERE account = $1 AND id = $2;",
+        let deleted = sqlx::query!(
+            "DELETE FROM apps WHERE account = $1 AND id = $2 RETURNING api_key;",
             account,
             id
         )
-        .execute(&db::get_pool()?)
-        .await?;
-        if 0 == n.rows_affected() {
-            Err(anyhow!("App not found"))
-        } else {
-            Ok(())
+        .fetch_optional(&db::get_pool()?)
+        .await?
+        .ok_or_else(|| anyhow!("App not found"))?;
+        if let Ok(mut keys) = KEYS.write() {
+            keys.remove(&deleted.api_key);
         }
+        Ok(())
+    }
+
+    pub async fn get(account: &str, id: i32) -> Result<App> {
+        let a = sqlx::query!(
+            "SELECT
+                account, id, name, description, chain, network, tier, api_key,
+                created_at, http_link, websocket_link
+            FROM apps
+            WHERE
+                account = $1 AND id = $2;",
+            account,
+            id,
+        )
+        .fetch_optional(&db::get_pool()?)
+        .await?
+        .ok_or_else(|| anyhow!("App not found"))?;
+        Ok(App {
+            account: a.account,
+            id: a.id,
+            name: a.name,
+            description: a.description,
+            chain: a.chain,
+            network: a.network,
+            tier: a.tier.parse().unwrap_or_default(),
+            api_key: a.api_key,
+            created_at: a.created_at,
+            http_link: a.http_link,
+            websocket_link: a.websocket_link,
+            ..Default::default()
+        })
     }
 
     pub async fn get_total(account: &str) -> Result<i64> {
@@ -80,14 +157,15 @@ impl App {
         .ok_or_else(|| anyhow!("Failed to get total"))
     }
 
-    pub async fn get_with_page(account: &str, page: i64, size: i64) -> Result<Vec<App>> {
+    /// 分页获取应用和它们的用量，用量的日期按 tz 时区计算
+    pub async fn get_with_page(account: &str, page: i64, size: i64, tz: Tz) -> Result<Vec<App>> {
         if page <= 0 {
             return Err(anyhow!("Page must be greater than 0"));
         }
         let offset = (page - 1) * size;
         let apps = sqlx::query!(
             "SELECT
-                account, id, name, description, chain, network, api_key,
+                account, id, name, description, chain, network, tier, api_key,
                 created_at, http_link, websocket_link
             FROM apps
             WHERE
@@ -111,15 +189,20 @@ impl App {
                 description: a.description,
                 chain: a.chain.clone(),
                 network: a.network,
+                tier: a.tier.parse().unwrap_or_default(),
                 api_key: a.api_key,
                 created_at: a.created_at,
                 http_link: a.http_link,
                 websocket_link: a.websocket_link,
                 ..Default::default()
             };
-            app.generate_code_example(a.chain.parse().unwrap_or(ChainEnum::Ethereum));
-            app.get_total_requests_today().await?;
-            app.get_dayly_requests_7days().await?;
+            app.generate_code_example();
+            app.get_total_requests_today(tz).await?;
+            app.get_dayly_requests_7days(tz).await?;
+            app.get_chain_mismatch_requests_today(tz).await?;
+            app.get_compute_units_today(tz);
+            app.anomalies = anomaly::flags(&app.api_key);
+            app.network_notice = NetworkNotice::of(&app.chain, &app.network);
             result.push(app);
         }
         Ok(result)
@@ -129,12 +212,12 @@ impl App {
         sqlx::query!(
             "INSERT INTO apps (
                 account, id, name, description,
-                chain, network, api_key,
+                chain, network, tier, api_key,
                 created_at, http_link, websocket_link
             ) VALUES (
                 $1, $2, $3, $4,
-                $5, $6, $7,
-                $8, $9, $10
+                $5, $6, $7, $8,
+                $9, $10, $11
             );",
             self.account,
             self.id,
@@ -142,6 +225,7 @@ impl App {
             self.description,
             self.chain,
             self.network,
+            self.tier.to_string(),
             self.api_key,
             self.created_at,
             self.http_link,
@@ -152,40 +236,39 @@ impl App {
         Ok(())
     }
 
-    fn generate_key(&mut self) -> Result<()> {
+    /// 地址使用应用所在网络的地址，nginx 把它交给 api 代理，代理按应用的节点类型选择节点池
+    fn generate_key(&mut self, network: &NetworkConfig) {
         let uid = uuid::Uuid::new_v4();
         self.api_key = format!(
             "{:x}",
             md5::compute(format!("{}-{}-{}", self.account, self.id, uid))
         );
-        let chain = match self.chain.parse::<chain::ChainEnum>() {
-            Ok(c) => c,
-            Err(_) => return Err(anyhow!("Chain not found")),
-        };
-        let chain = chain::Chain::new(chain);
-        self.http_link = format!("{}/{}", chain.http_address, self.api_key);
-        self.websocket_link = format!("{}/{}", chain.websocket_address, self.api_key);
-        Ok(())
+        self.http_link = format!("{}/{}", network.http_address(), self.api_key);
+        self.websocket_link = format!("{}/{}", network.websocket_address(), self.api_key);
     }
 
-    pub fn generate_code_example(&mut self, chain_type: ChainEnum) {
-        self.code_examples = examples::get_code_example(&self.http_link, chain_type);
+    /// 按注册表中链的模板生成，链已经从注册表中移除时使用以太坊的示例
+    pub fn generate_code_example(&mut self) {
+        self.code_examples = match registry::get().chain(&self.chain) {
+            Some(chain) => chain.code_example(&self.http_link),
+            None => examples::get_builtin_example("ethereum", &self.http_link).unwrap_or_default(),
+        };
     }
 
-    async fn get_total_requests_today(&mut self) -> Result<()> {
-        let log = match log_parse::query::QueryLog::query_today(&self.api_key).await {
+    async fn get_total_requests_today(&mut self, tz: Tz) -> Result<()> {
+        let log = match log_parse::query::QueryLog::query_today(&self.api_key, tz).await {
             Ok(l) => l,
             Err(_) => {
                 tracing::error!("Failed to get total requests today");
                 return Ok(());
             }
         };
-        self.total_requests_today = log.result.len() as i32;
+        self.total_requests_today = log.total as i32;
         Ok(())
     }
 
-    async fn get_dayly_requests_7days(&mut self) -> Result<()> {
-        let logs = match log_parse::query::QueryLog::query_7days(&self.api_key).await {
+    async fn get_dayly_requests_7days(&mut self, tz: Tz) -> Result<()> {
+        let logs = match log_parse::query::QueryLog::query_7days(&self.api_key, tz).await {
             Ok(l) => l,
             Err(_) => {
                 tracing::error!("Failed to get dayly requests 7days");
@@ -194,10 +277,75 @@ impl App {
         };
         let mut result = Vec::new();
         logs.iter().for_each(|log| {
-            result.push(log.result.len() as i32);
+            result.push(log.total as i32);
         });
         result.reverse();
         self.dayly_requests_7days = result;
         Ok(())
     }
+
+    fn get_compute_units_today(&mut self, tz: Tz) {
+        let today = Utc::now().with_timezone(&tz).date_naive();
+        let units =
+            usage::index::read(|index| index.day(&self.api_key, today, tz).total.compute_units);
+        match units {
+            Ok(units) => self.compute_units_today = units,
+            Err(e) => tracing::error!("Failed to get compute units today: {}", e),
+        }
+    }
+
+    async fn get_chain_mismatch_requests_today(&mut self, tz: Tz) -> Result<()> {
+        let query =
+            log_parse::query::QueryLog::query_chain_mismatch_today(&self.api_key, &self.chain, tz);
+        let log = match query.await {
+            Ok(l) => l,
+            Err(_) => {
+                tracing::error!("Failed to get chain mismatch requests today");
+                return Ok(());
+            }
+        };
+        self.chain_mismatch_requests_today = log.total as i32;
+        Ok(())
+    }
+}
+
+/// 启动时读取所有应用的 api key
+pub async fn load_keys() -> Result<()> {
+    let rows = sqlx::query!("SELECT api_key, account, tier FROM apps;")
+        .fetch_all(&db::get_pool()?)
+        .await?;
+    let mut keys = KEYS.write().map_err(|e| anyhow!(e.to_string()))?;
+    for row in rows {
+        match row.tier.parse() {
+            Ok(tier) => {
+                let key = ApiKey {
+                    account: row.account,
+                    tier,
+                };
+                keys.insert(row.api_key, key);
+            }
+            Err(e) => tracing::error!("app {} has invalid tier: {}", row.api_key, e),
+        }
+    }
+    Ok(())
+}
+
+fn set_key(api_key: &str, account: &str, tier: Tier) {
+    if let Ok(mut keys) = KEYS.write() {
+        let key = ApiKey {
+            account: account.to_string(),
+            tier,
+        };
+        keys.insert(api_key.to_string(), key);
+    }
+}
+
+/// api key 属于哪个应用，找不到应用时返回 None
+pub fn key(api_key: &str) -> Option<ApiKey> {
+    KEYS.read().ok()?.get(api_key).cloned()
+}
+
+/// api key 对应应用的节点类型，找不到应用时是全节点
+pub fn tier(api_key: &str) -> Tier {
+    key(api_key).map(|k| k.tier).unwrap_or_default()
 }
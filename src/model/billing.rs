@@ -0,0 +1,402 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone, Utc};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgExecutor, Postgres, Transaction};
+
+use super::{db, registry::Tier};
+
+static PRICING: OnceCell<Pricing> = OnceCell::new();
+/// 余额已经用完的账户，只在 REQUIRE_CREDITS=true 时更新
+static EXHAUSTED: Lazy<RwLock<HashSet<String>>> = Lazy::new(Default::default);
+
+/// 用量每分钟写入一次，余额也每分钟检查一次
+const EXHAUSTED_INTERVAL_SECS: u64 = 60;
+
+/// 没有配置价格表时每个请求 1 个计算单位，和按请求数计费一致
+const DEFAULT_UNITS: i64 = 1;
+
+/// 计算单位价格表，例如
+///
+/// ```toml
+/// default = 10
+///
+/// [methods]
+/// eth_chainId = 1
+/// eth_getLogs = 75
+///
+/// [chains.near]
+/// default = 20
+/// methods = { query = 15 }
+///
+/// [tiers]
+/// archive = 2
+/// ```
+///
+/// 查找顺序是链的方法、全局的方法、链的默认值、全局的默认值，链名和方法名不区分大小写。
+/// 归档节点的请求再乘以 tiers 中的倍数，没有配置时是 1
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pricing {
+    #[serde(default = "default_units")]
+    pub default: i64,
+    #[serde(default)]
+    pub methods: HashMap<String, i64>,
+    #[serde(default)]
+    pub chains: HashMap<String, ChainPricing>,
+    /// 每种节点类型的计算单位倍数
+    #[serde(default)]
+    pub tiers: HashMap<Tier, i64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ChainPricing {
+    pub default: Option<i64>,
+    #[serde(default)]
+    pub methods: HashMap<String, i64>,
+}
+
+fn default_units() -> i64 {
+    DEFAULT_UNITS
+}
+
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            default: DEFAULT_UNITS,
+            methods: HashMap::new(),
+            chains: HashMap::new(),
+            tiers: HashMap::new(),
+        }
+    }
+}
+
+impl Pricing {
+    pub fn parse(content: &str) -> Result<Self> {
+        let pricing: Pricing = toml::from_str(content)?;
+        let lower = |methods: HashMap<String, i64>| {
+            methods
+                .into_iter()
+                .map(|(m, units)| (m.to_lowercase(), units))
+                .collect()
+        };
+        Ok(Self {
+            default: pricing.default,
+            methods: lower(pricing.methods),
+            chains: pricing
+                .chains
+                .into_iter()
+                .map(|(chain, p)| {
+                    let p = ChainPricing {
+                        default: p.default,
+                        methods: lower(p.methods),
+                    };
+                    (chain.to_lowercase(), p)
+                })
+                .collect(),
+            tiers: pricing.tiers,
+        })
+    }
+
+    /// 从 COMPUTE_UNITS_FILE 指定的文件读取，默认是 compute_units.toml，文件不存在时使用默认价格
+    pub fn from_env() -> Result<Self> {
+        let path =
+            std::env::var("COMPUTE_UNITS_FILE").unwrap_or_else(|_| "compute_units.toml".into());
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content).map_err(|e| anyhow!("{} invalid: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!("read {} failed: {}", path, e)),
+        }
+    }
+
+    pub fn method_units(&self, chain: &str, method: &str) -> i64 {
+        let chain = self.chains.get(&chain.to_lowercase());
+        let method = method.to_lowercase();
+        chain
+            .and_then(|c| c.methods.get(&method))
+            .or_else(|| self.methods.get(&method))
+            .copied()
+            .or_else(|| chain.and_then(|c| c.default))
+            .unwrap_or(self.default)
+    }
+
+    /// 一个请求的计算单位，批量请求是每个方法之和，不是 JSON-RPC 的请求按链的默认值
+    pub fn request_units(&self, chain: &str, methods: &[String]) -> i64 {
+        if methods.is_empty() {
+            return self
+                .chains
+                .get(&chain.to_lowercase())
+                .and_then(|c| c.default)
+                .unwrap_or(self.default);
+        }
+        methods.iter().map(|m| self.method_units(chain, m)).sum()
+    }
+
+    pub fn tier_multiplier(&self, tier: Tier) -> i64 {
+        self.tiers.get(&tier).copied().unwrap_or(1)
+    }
+}
+
+/// 启动时读取并校验价格表，配置错误时直接报错
+pub fn init() -> Result<()> {
+    let pricing = Pricing::from_env()?;
+    let _ = PRICING.set(pricing);
+    Ok(())
+}
+
+/// 按价格表计算一个请求的计算单位，子命令没有调用 init 时也会读取价格表
+pub fn compute_units(chain: &str, tier: Tier, methods: &[String]) -> i64 {
+    let pricing = PRICING.get_or_init(|| {
+        Pricing::from_env().unwrap_or_else(|e| {
+            tracing::error!("load compute units failed: {}", e);
+            Pricing::default()
+        })
+    });
+    pricing.request_units(chain, methods) * pricing.tier_multiplier(tier)
+}
+
+/// 充值，返回充值后的余额
+pub async fn top_up(account: &str, amount: i64, note: &str) -> Result<i64> {
+    if amount <= 0 {
+        return Err(anyhow!("amount must be greater than 0"));
+    }
+    let mut tx = db::get_pool()?.begin().await?;
+    sqlx::query!(
+        "INSERT INTO credit_topups (account, amount, note) VALUES ($1, $2, $3);",
+        account,
+        amount,
+        note,
+    )
+    .execute(&mut tx)
+    .await?;
+    let balance = balance_at(&mut tx, account, None).await?;
+    tx.commit().await?;
+    if balance > 0 {
+        if let Ok(mut exhausted) = EXHAUSTED.write() {
+            exhausted.remove(account);
+        }
+    }
+    Ok(balance)
+}
+
+/// 扣除每个 api key 每小时用掉的计算单位，和用量写在同一个事务中，不会重复扣费。
+/// 已经删除的应用找不到账户，不再扣费
+pub async fn debit(
+    tx: &mut Transaction<'_, Postgres>,
+    units: &HashMap<(String, String, DateTime<Utc>), i64>,
+) -> Result<()> {
+    for ((api_key, chain, hour), units) in units {
+        sqlx::query!(
+            "INSERT INTO credit_debits (account, api_key, chain, hour, compute_units)
+            SELECT account, api_key, $2, $3, $4 FROM apps WHERE api_key = $1
+            ON CONFLICT (api_key, chain, hour)
+            DO UPDATE SET compute_units = credit_debits.compute_units + EXCLUDED.compute_units;",
+            api_key,
+            chain,
+            hour,
+            units,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// 账户当前的余额，没有充值过也没有用量时为 0
+pub async fn balance(account: &str) -> Result<i64> {
+    balance_at(&db::get_pool()?, account, None).await
+}
+
+/// 充值减去扣费，before 为 None 时计算全部记录。余额和账单都从这两张表得出，不会对不上
+async fn balance_at<'e>(
+    executor: impl PgExecutor<'e>,
+    account: &str,
+    before: Option<DateTime<Utc>>,
+) -> Result<i64> {
+    let balance = sqlx::query_scalar!(
+        "SELECT ((SELECT COALESCE(SUM(amount), 0) FROM credit_topups
+                WHERE account = $1 AND ($2::timestamptz IS NULL OR created_at < $2))
+            - (SELECT COALESCE(SUM(compute_units), 0) FROM credit_debits
+                WHERE account = $1 AND ($2::timestamptz IS NULL OR hour < $2)))::bigint;",
+        account,
+        before,
+    )
+    .fetch_one(executor)
+    .await?;
+    Ok(balance.unwrap_or(0))
+}
+
+/// 配置 REQUIRE_CREDITS=true 后，定期找出余额用完的账户，代理拒绝它们的请求
+pub fn watch() {
+    if std::env::var("REQUIRE_CREDITS").as_deref() != Ok("true") {
+        return;
+    }
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(EXHAUSTED_INTERVAL_SECS));
+        loop {
+            ticker.tick().await;
+            if let Err(e) = refresh_exhausted().await {
+                tracing::error!("refresh exhausted accounts failed: {}", e);
+            }
+        }
+    });
+}
+
+async fn refresh_exhausted() -> Result<()> {
+    let accounts = sqlx::query_scalar!(
+        "SELECT account FROM (SELECT DISTINCT account FROM apps) a
+        WHERE (SELECT COALESCE(SUM(amount), 0) FROM credit_topups t WHERE t.account = a.account)
+            <= (SELECT COALESCE(SUM(compute_units), 0) FROM credit_debits d
+                WHERE d.account = a.account);"
+    )
+    .fetch_all(&db::get_pool()?)
+    .await?;
+    let mut exhausted = EXHAUSTED.write().map_err(|e| anyhow!(e.to_string()))?;
+    *exhausted = accounts.into_iter().collect();
+    Ok(())
+}
+
+/// 账户的余额是否已经用完，没有开启 REQUIRE_CREDITS 时总是 false
+pub fn is_exhausted(account: &str) -> bool {
+    EXHAUSTED
+        .read()
+        .is_ok_and(|exhausted| exhausted.contains(account))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TopUp {
+    pub amount: i64,
+    pub note: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatementLine {
+    pub api_key: String,
+    pub chain: String,
+    pub compute_units: i64,
+}
+
+/// 月度账单，月份按 UTC 计算
+#[derive(Debug, Clone, Serialize)]
+pub struct Statement {
+    pub account: String,
+    pub month: String,
+    pub opening_balance: i64,
+    pub top_ups: i64,
+    pub compute_units: i64,
+    pub closing_balance: i64,
+    pub top_up_entries: Vec<TopUp>,
+    pub usage: Vec<StatementLine>,
+}
+
+/// month 是 `2022-12` 这样的月份
+pub async fn statement(account: &str, month: &str) -> Result<Statement> {
+    let first = NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .map_err(|_| anyhow!("month invalid: {}", month))?;
+    let next = if first.month() == 12 {
+        NaiveDate::from_ymd_opt(first.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(first.year(), first.month() + 1, 1)
+    }
+    .ok_or_else(|| anyhow!("month invalid: {}", month))?;
+    let start = Utc.from_utc_datetime(&first.and_hms_opt(0, 0, 0).unwrap_or_default());
+    let end = Utc.from_utc_datetime(&next.and_hms_opt(0, 0, 0).unwrap_or_default());
+    let pool = db::get_pool()?;
+
+    let opening_balance = balance_at(&pool, account, Some(start)).await?;
+
+    let top_up_entries: Vec<TopUp> = sqlx::query!(
+        "SELECT amount, note, created_at FROM credit_topups
+        WHERE account = $1 AND created_at >= $2 AND created_at < $3
+        ORDER BY created_at;",
+        account,
+        start,
+        end,
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| TopUp {
+        amount: r.amount,
+        note: r.note,
+        created_at: r.created_at,
+    })
+    .collect();
+
+    let usage: Vec<StatementLine> = sqlx::query!(
+        "SELECT api_key, chain, SUM(compute_units)::bigint AS compute_units
+        FROM credit_debits
+        WHERE account = $1 AND hour >= $2 AND hour < $3
+        GROUP BY api_key, chain
+        ORDER BY api_key, chain;",
+        account,
+        start,
+        end,
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|r| StatementLine {
+        api_key: r.api_key,
+        chain: r.chain,
+        compute_units: r.compute_units.unwrap_or(0),
+    })
+    .collect();
+
+    let top_ups = top_up_entries.iter().map(|t| t.amount).sum();
+    let compute_units = usage.iter().map(|u| u.compute_units).sum();
+    Ok(Statement {
+        account: account.to_string(),
+        month: first.format("%Y-%m").to_string(),
+        opening_balance,
+        top_ups,
+        compute_units,
+        closing_balance: opening_balance + top_ups - compute_units,
+        top_up_entries,
+        usage,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pricing() {
+        let pricing = Pricing::parse(
+            r#"
+            default = 10
+
+            [methods]
+            eth_chainId = 1
+            eth_getLogs = 75
+
+            [chains.Near]
+            default = 20
+            methods = { query = 15 }
+
+            [tiers]
+            archive = 3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(pricing.method_units("Ethereum", "eth_chainId"), 1);
+        assert_eq!(pricing.method_units("Ethereum", "eth_getBalance"), 10);
+        assert_eq!(pricing.method_units("Near", "query"), 15);
+        assert_eq!(pricing.method_units("Near", "block"), 20);
+        let batch = vec!["eth_chainId".to_string(), "eth_getLogs".to_string()];
+        assert_eq!(pricing.request_units("Ethereum", &batch), 76);
+        assert_eq!(pricing.request_units("Aptos", &[]), 10);
+        assert_eq!(pricing.request_units("Near", &[]), 20);
+        assert_eq!(Pricing::default().request_units("Ethereum", &batch), 2);
+        assert_eq!(pricing.tier_multiplier(Tier::Archive), 3);
+        assert_eq!(pricing.tier_multiplier(Tier::Full), 1);
+        assert!(Pricing::parse("[tiers]\nlight = 1").is_err());
+    }
+}
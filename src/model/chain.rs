@@ -1,8 +1,13 @@
-use std::{fmt, str::FromStr};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+use super::{
+    health,
+    registry::{self, ChainConfig, ChainId, Currency, Family, NetworkConfig, NetworkStatus, Tier},
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ChainEnum {
     Ethereum,
     Bsc,
@@ -17,19 +22,22 @@ pub enum ChainEnum {
 }
 
 impl ChainEnum {
+    pub const ALL: [ChainEnum; 10] = [
+        ChainEnum::Ethereum,
+        ChainEnum::Bsc,
+        ChainEnum::Polygon,
+        ChainEnum::Avalanche,
+        ChainEnum::Optimism,
+        ChainEnum::ZkSync,
+        ChainEnum::StarkWare,
+        ChainEnum::Near,
+        ChainEnum::Aptos,
+        ChainEnum::Sui,
+    ];
+
+    /// 注册表中的所有链，包括不在枚举中的链
     pub fn get_all() -> Vec<Chain> {
-        vec![
-            Chain::new(ChainEnum::Ethereum),
-            Chain::new(ChainEnum::Bsc),
-            Chain::new(ChainEnum::Polygon),
-            Chain::new(ChainEnum::Avalanche),
-            Chain::new(ChainEnum::Optimism),
-            Chain::new(ChainEnum::ZkSync),
-            Chain::new(ChainEnum::StarkWare),
-            Chain::new(ChainEnum::Near),
-            Chain::new(ChainEnum::Aptos),
-            Chain::new(ChainEnum::Sui),
-        ]
+        registry::get().chains.iter().map(Chain::from).collect()
     }
 }
 
@@ -73,39 +81,180 @@ impl FromStr for ChainEnum {
 #[derive(Deserialize, Serialize)]
 pub struct Chain {
     pub name: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
     pub http_address: String,
     pub websocket_address: String,
+    /// 原来的枚举格式，只包含能用 NetworkEnum 表示的网络
     pub networks: Vec<NetworkEnum>,
+    /// 注册表中所有没有下线的网络的名字
+    pub network_names: Vec<String>,
+    /// 每个网络提供的节点类型
+    pub tiers: BTreeMap<String, Vec<Tier>>,
     pub is_available: bool,
+    /// 有网络的节点落后太多
+    pub degraded: bool,
+    /// 各网络中落后最多的块数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag: Option<u64>,
+    /// 主网的 chain id、代币和浏览器
+    #[serde(flatten)]
+    pub metadata: Metadata,
 }
 
 impl Chain {
+    /// 从注册表中读取，地址是主网的地址，注册表中没有这条链时不可用
     pub fn new(chain: ChainEnum) -> Self {
-        match chain {
-            ChainEnum::Ethereum => Self {
+        match registry::get().chain(&chain.to_string()) {
+            Some(config) => Self::from(config),
+            None => Self {
                 name: chain.to_string(),
-                http_address: get_chain_link(&chain).0,
-                websocket_address: get_chain_link(&chain).1,
-                networks: vec![NetworkEnum::Mainnet, NetworkEnum::Testnet(Testnet::Goerli)],
-                is_available: get_chain_link(&chain).0 != "Not supported yet"
-                    || get_chain_link(&chain).1 != "Not supported yet",
-            },
-            _ => Self {
-                name: chain.to_string(),
-                http_address: get_chain_link(&chain).0,
-                websocket_address: get_chain_link(&chain).1,
-                networks: vec![NetworkEnum::Mainnet],
-                is_available: get_chain_link(&chain).0 != "Not supported yet"
-                    || get_chain_link(&chain).1 != "Not supported yet",
+                description: String::new(),
+                http_address: registry::NOT_SUPPORTED.to_string(),
+                websocket_address: registry::NOT_SUPPORTED.to_string(),
+                networks: vec![],
+                network_names: vec![],
+                tiers: BTreeMap::new(),
+                is_available: false,
+                degraded: false,
+                lag: None,
+                metadata: Metadata::default(),
             },
         }
     }
+
     pub fn have_network(&self, network: &str) -> bool {
-        let network = match network.parse::<NetworkEnum>() {
-            Ok(n) => n,
-            Err(_) => return false,
-        };
-        self.networks.iter().any(|n| n == &network)
+        let network = registry::normalize_network(network);
+        self.network_names
+            .iter()
+            .any(|n| registry::normalize_network(n) == network)
+    }
+}
+
+impl From<&ChainConfig> for Chain {
+    fn from(config: &ChainConfig) -> Self {
+        let health: Vec<_> = config
+            .networks
+            .iter()
+            .filter(|n| n.status != NetworkStatus::Retired)
+            .map(|n| health::network(config, n))
+            .collect();
+        Self {
+            name: config.name.clone(),
+            description: config.description.clone(),
+            http_address: config.mainnet().http_address(),
+            websocket_address: config.mainnet().websocket_address(),
+            networks: network_enums(config),
+            network_names: config.network_names(),
+            tiers: config
+                .networks
+                .iter()
+                .filter(|n| n.status != NetworkStatus::Retired)
+                .map(|n| (n.name.clone(), n.tiers()))
+                .collect(),
+            is_available: config.networks.iter().any(|n| is_available(config, n)),
+            degraded: health.iter().any(|h| h.degraded),
+            lag: health.iter().filter_map(|h| h.lag).max(),
+            metadata: Metadata::new(config, config.mainnet()),
+        }
+    }
+}
+
+/// 没有下线的网络中能用 NetworkEnum 表示的网络，给只认识枚举的旧客户端
+pub fn network_enums(config: &ChainConfig) -> Vec<NetworkEnum> {
+    config
+        .network_names()
+        .iter()
+        .filter_map(|n| n.parse().ok())
+        .collect()
+}
+
+/// 一个网络和它的地址，没有地址的网络不能创建应用
+#[derive(Deserialize, Serialize)]
+pub struct Network {
+    pub name: String,
+    pub status: NetworkStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub successor: Option<String>,
+    pub http_address: String,
+    pub websocket_address: String,
+    pub tiers: Vec<Tier>,
+    pub is_available: bool,
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag: Option<u64>,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+impl Network {
+    pub fn new(chain: &ChainConfig, config: &NetworkConfig) -> Self {
+        let health = health::network(chain, config);
+        Self {
+            name: config.name.clone(),
+            status: config.status,
+            successor: config.successor.clone(),
+            http_address: config.http_address(),
+            websocket_address: config.websocket_address(),
+            tiers: config.tiers(),
+            is_available: is_available(chain, config),
+            degraded: health.degraded,
+            lag: health.lag,
+            metadata: Metadata::new(chain, config),
+        }
+    }
+}
+
+/// 钱包配置需要的信息，没有配置的字段不返回
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub family: Family,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<ChainId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explorer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logo: Option<String>,
+}
+
+impl Metadata {
+    /// 网络没有配置代币时使用链的代币
+    pub fn new(chain: &ChainConfig, network: &NetworkConfig) -> Self {
+        Self {
+            family: chain.family,
+            chain_id: network.chain_id.clone(),
+            currency: network.currency.clone().or_else(|| chain.currency.clone()),
+            explorer: network.explorer.clone(),
+            docs: chain.docs.clone(),
+            logo: chain.logo.clone(),
+        }
+    }
+}
+
+/// 配置了地址，并且健康检查没有发现节点全部不可用
+fn is_available(chain: &ChainConfig, network: &NetworkConfig) -> bool {
+    network.is_available() && health::network(chain, network).up != Some(false)
+}
+
+/// 应用所在的网络已经弃用或下线时的提示
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NetworkNotice {
+    pub status: NetworkStatus,
+    pub successor: Option<String>,
+}
+
+impl NetworkNotice {
+    /// 网络正常时返回 None
+    pub fn of(chain: &str, network: &str) -> Option<Self> {
+        let network = registry::get().chain(chain)?.network(network)?;
+        (network.status != NetworkStatus::Active).then(|| Self {
+            status: network.status,
+            successor: network.successor.clone(),
+        })
     }
 }
 
@@ -155,12 +304,3 @@ impl FromStr for NetworkEnum {
         }
     }
 }
-
-/// 返回链的 http 和 websocket 地址
-fn get_chain_link(chain: &ChainEnum) -> (String, String) {
-    let chain_http = format!("{}_HTTP", chain.to_string().to_uppercase());
-    let chain_ws = format!("{}_WS", chain.to_string().to_uppercase());
-    let http_link = std::env::var(chain_http).unwrap_or_else(|_| "Not supported yet".to_string());
-    let ws_link = std::env::var(chain_ws).unwrap_or_else(|_| "Not supported yet".to_string());
-    (http_link, ws_link)
-}
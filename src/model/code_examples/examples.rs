@@ -11,6 +11,7 @@ use super::optimism::get_optimism_examples;
 use super::polygon::get_polygon_examples;
 use super::starkware::get_starkware_examples;
 use super::sui::get_sui_examples;
+use super::tools::generate;
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default)]
 pub struct CodeExample {
@@ -40,17 +41,46 @@ impl FromStr for Language {
     }
 }
 
+/// 内置的代码示例，注册表中的 `examples` 按名字引用
+pub const BUILTIN: [&str; 9] = [
+    "ethereum",
+    "bsc",
+    "polygon",
+    "avalanche",
+    "optimism",
+    "starkware",
+    "near",
+    "aptos",
+    "sui",
+];
+
 pub fn get_code_example(link: &str, chain_type: ChainEnum) -> CodeExample {
-    match chain_type {
-        ChainEnum::Ethereum => get_ethereum_examples(link),
-        ChainEnum::Sui => get_sui_examples(link),
-        ChainEnum::Avalanche => get_avalanche_examples(link),
-        ChainEnum::Optimism => get_optimism_examples(link),
-        ChainEnum::Near => get_near_examples(link),
-        ChainEnum::Aptos => get_aptos_examples(link),
-        ChainEnum::Polygon => get_polygon_examples(link),
-        ChainEnum::StarkWare => get_starkware_examples(link),
-        ChainEnum::Bsc => get_bsc_examples(link),
-        _ => get_ethereum_examples(link),
+    get_builtin_example(&chain_type.to_string().to_lowercase(), link)
+        .unwrap_or_else(|| get_ethereum_examples(link))
+}
+
+pub fn get_builtin_example(name: &str, link: &str) -> Option<CodeExample> {
+    let example = match name {
+        "ethereum" => get_ethereum_examples(link),
+        "sui" => get_sui_examples(link),
+        "avalanche" => get_avalanche_examples(link),
+        "optimism" => get_optimism_examples(link),
+        "near" => get_near_examples(link),
+        "aptos" => get_aptos_examples(link),
+        "polygon" => get_polygon_examples(link),
+        "starkware" => get_starkware_examples(link),
+        "bsc" => get_bsc_examples(link),
+        _ => return None,
+    };
+    Some(example)
+}
+
+/// 用注册表中自定义的模板生成代码示例，模板中用 `${link}` 表示应用的地址
+pub fn from_templates(templates: &CodeExample, link: &str) -> CodeExample {
+    CodeExample {
+        js: generate(&templates.js, link),
+        cli: generate(&templates.cli, link),
+        python: generate(&templates.python, link),
+        go: generate(&templates.go, link),
     }
 }
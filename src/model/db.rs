@@ -4,11 +4,14 @@ use anyhow::{anyhow, Result};
 use once_cell::sync::OnceCell;
 use sqlx::{
     postgres::{PgConnectOptions, PgPoolOptions},
-    ConnectOptions, Pool, Postgres,
+    ConnectOptions, Executor, Pool, Postgres,
 };
 
 static POOL: OnceCell<Pool<Postgres>> = OnceCell::new();
 
+/// 和 docker 的 initdb 脚本是同一个文件，initdb 只在空数据库上执行一次
+const SCHEMA: &str = include_str!("../../compose/node-services/db_init/init.sql");
+
 pub async fn init() -> Result<(), Pool<Postgres>> {
     let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     tracing::info!("Connecting to database: {}", url);
@@ -40,6 +43,12 @@ async fn set_pool_with_log(url: &str) -> Result<(), Pool<Postgres>> {
     })
 }
 
+/// 启动时执行建表脚本，脚本中的语句都是幂等的，已有的数据库会补上新的表和列
+pub async fn migrate() -> Result<()> {
+    get_pool()?.execute(SCHEMA).await?;
+    Ok(())
+}
+
 pub fn get_pool() -> Result<Pool<Postgres>> {
     match POOL.get() {
         Some(pool) => Ok(pool.clone()),
@@ -0,0 +1,639 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use super::registry::{self, ChainConfig, NetworkConfig, NetworkStatus, Probe, Registry};
+
+static STATE: Lazy<RwLock<HealthState>> = Lazy::new(Default::default);
+
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+const DEFAULT_TIMEOUT_MS: u64 = 5000;
+/// 每个节点保留最近 120 次检查，默认间隔下是一个小时
+const HISTORY: usize = 120;
+
+/// 某条链某个网络的一个节点
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Upstream {
+    pub chain: String,
+    pub network: String,
+    /// 节点地址可能带着服务商的 key，不输出到状态和指标中
+    #[serde(skip)]
+    pub url: String,
+    /// 对外展示的名字，例如 `http-0`、`archive-1`、`trace-0`、`reference-0`
+    pub label: String,
+    /// 只用来对比块高的外部节点，不算在可用性中
+    pub reference: bool,
+}
+
+/// 一次健康检查的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+    pub latency_ms: u64,
+    /// 节点返回的最新块高，`rpc.discover` 没有块高
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// 一个节点最近的状态，history 按时间从旧到新
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    #[serde(flatten)]
+    pub upstream: Upstream,
+    pub up: bool,
+    pub latency_ms: u64,
+    pub checked_at: DateTime<Utc>,
+    pub height: Option<u64>,
+    /// 比同一个网络的其它节点和参照节点中最高的块落后多少
+    pub lag: Option<u64>,
+    /// 最近几次检查中成功的比例
+    pub uptime: f64,
+    pub history: Vec<Check>,
+}
+
+/// 从节点状态中取出指标的值
+type Gauge = fn(&UpstreamStatus) -> Option<f64>;
+
+#[derive(Debug, Default)]
+pub struct HealthState {
+    upstreams: HashMap<Upstream, VecDeque<Check>>,
+}
+
+impl HealthState {
+    pub fn record(&mut self, upstream: Upstream, check: Check) {
+        let history = self.upstreams.entry(upstream).or_default();
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(check);
+    }
+
+    /// 每个节点最近一次成功的检查
+    fn latest_ok(&self, chain: &str, network: &str) -> impl Iterator<Item = (&Upstream, &Check)> {
+        self.latest(chain, network).filter(|(_, check)| check.ok)
+    }
+
+    fn latest(&self, chain: &str, network: &str) -> impl Iterator<Item = (&Upstream, &Check)> {
+        let (chain, network) = (chain.to_string(), network.to_string());
+        self.upstreams
+            .iter()
+            .filter(move |(u, _)| u.chain == chain && u.network == network)
+            .filter_map(|(u, history)| Some((u, history.back()?)))
+    }
+
+    /// 网络中所有节点和参照节点的最高块
+    fn head(&self, chain: &str, network: &str) -> Option<u64> {
+        self.latest_ok(chain, network)
+            .filter_map(|(_, check)| check.height)
+            .max()
+    }
+
+    fn lag(&self, upstream: &Upstream, check: &Check) -> Option<u64> {
+        let height = check.height.filter(|_| check.ok)?;
+        let head = self.head(&upstream.chain, &upstream.network)?;
+        Some(head.saturating_sub(height))
+    }
+
+    /// 网络中有一个节点最近一次检查成功就是可用的，还没有检查过时 up 是 None。
+    /// lag 是落后最少的可用节点落后的块数，超过 max_lag 时网络降级
+    pub fn network(&self, chain: &str, network: &str, max_lag: u64) -> NetworkHealth {
+        let upstreams: Vec<_> = self
+            .latest(chain, network)
+            .filter(|(u, _)| !u.reference)
+            .collect();
+        let up = upstreams
+            .iter()
+            .map(|(_, check)| check.ok)
+            .reduce(|a, b| a || b);
+        let lag = upstreams
+            .iter()
+            .filter_map(|(u, check)| self.lag(u, check))
+            .min();
+        NetworkHealth {
+            up,
+            lag,
+            degraded: lag.is_some_and(|lag| lag > max_lag),
+        }
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        let mut status: Vec<UpstreamStatus> = self
+            .upstreams
+            .iter()
+            .filter_map(|(upstream, history)| {
+                let latest = history.back()?;
+                let ok = history.iter().filter(|c| c.ok).count();
+                Some(UpstreamStatus {
+                    upstream: upstream.clone(),
+                    up: latest.ok,
+                    latency_ms: latest.latency_ms,
+                    checked_at: latest.at,
+                    height: latest.height,
+                    lag: self.lag(upstream, latest),
+                    uptime: ok as f64 / history.len() as f64,
+                    history: history.iter().cloned().collect(),
+                })
+            })
+            .collect();
+        status.sort_by(|a, b| {
+            (&a.upstream.chain, &a.upstream.network, &a.upstream.label).cmp(&(
+                &b.upstream.chain,
+                &b.upstream.network,
+                &b.upstream.label,
+            ))
+        });
+        status
+    }
+
+    /// Prometheus 文本格式的指标
+    pub fn metrics(&self, registry: &Registry) -> String {
+        let mut out = String::new();
+        let status = self.status();
+        let gauges: [(&str, &str, Gauge); 4] = [
+            (
+                "node_upstream_up",
+                "whether the last health check succeeded",
+                |s| Some(if s.up { 1.0 } else { 0.0 }),
+            ),
+            (
+                "node_upstream_latency_ms",
+                "latency of the last health check",
+                |s| Some(s.latency_ms as f64),
+            ),
+            (
+                "node_upstream_height",
+                "latest block height reported",
+                |s| s.height.map(|h| h as f64),
+            ),
+            (
+                "node_upstream_lag_blocks",
+                "blocks behind the highest node",
+                |s| s.lag.map(|l| l as f64),
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+            for s in &status {
+                let Some(value) = value(s) else {
+                    continue;
+                };
+                let _ = writeln!(
+                    out,
+                    "{}{{chain=\"{}\",network=\"{}\",upstream=\"{}\",reference=\"{}\"}} {}",
+                    name,
+                    escape(&s.upstream.chain),
+                    escape(&s.upstream.network),
+                    escape(&s.upstream.label),
+                    s.upstream.reference,
+                    value
+                );
+            }
+        }
+        let networks: Vec<_> = registry
+            .chains
+            .iter()
+            .flat_map(|c| c.networks.iter().map(move |n| (c, n)))
+            .map(|(c, n)| (c, n, self.network(&c.name, &n.name, c.max_lag)))
+            .filter(|(_, _, health)| health.up.is_some())
+            .collect();
+        let _ = writeln!(
+            out,
+            "# HELP node_network_lag_blocks blocks the best node is behind\n\
+            # TYPE node_network_lag_blocks gauge"
+        );
+        for (chain, network, health) in &networks {
+            if let Some(lag) = health.lag {
+                let _ = writeln!(
+                    out,
+                    "node_network_lag_blocks{{chain=\"{}\",network=\"{}\"}} {}",
+                    escape(&chain.name),
+                    escape(&network.name),
+                    lag
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP node_network_degraded whether the best node lags more than max_lag\n\
+            # TYPE node_network_degraded gauge"
+        );
+        for (chain, network, health) in &networks {
+            let _ = writeln!(
+                out,
+                "node_network_degraded{{chain=\"{}\",network=\"{}\"}} {}",
+                escape(&chain.name),
+                escape(&network.name),
+                u8::from(health.degraded)
+            );
+        }
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// 后台定时检查注册表中所有没有下线的网络的 http 节点，
+/// 间隔和超时由 HEALTH_CHECK_INTERVAL_SECS 和 HEALTH_CHECK_TIMEOUT_MS 配置
+pub fn init() {
+    let interval = env_u64("HEALTH_CHECK_INTERVAL_SECS", DEFAULT_INTERVAL_SECS);
+    let timeout = env_u64("HEALTH_CHECK_TIMEOUT_MS", DEFAULT_TIMEOUT_MS);
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_millis(timeout))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("create health check client failed: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        loop {
+            ticker.tick().await;
+            let checks = check_all(&client, targets(registry::get())).await;
+            match STATE.write() {
+                Ok(mut state) => {
+                    for (upstream, check) in checks {
+                        state.record(upstream, check);
+                    }
+                }
+                Err(e) => tracing::error!("record health check failed: {}", e),
+            }
+        }
+    });
+}
+
+fn env_u64(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// 一个网络的健康状况
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct NetworkHealth {
+    /// 没有配置节点或者还没有检查过时是 None
+    pub up: Option<bool>,
+    pub lag: Option<u64>,
+    pub degraded: bool,
+}
+
+pub fn network(chain: &ChainConfig, network: &NetworkConfig) -> NetworkHealth {
+    STATE
+        .read()
+        .map(|state| state.network(&chain.name, &network.name, chain.max_lag))
+        .unwrap_or_default()
+}
+
+/// 网络最近一次健康检查得到的最高块，还没有检查成功过时返回 None
+pub fn head(chain: &str, network: &str) -> Option<u64> {
+    STATE.read().ok()?.head(chain, network)
+}
+
+/// 某个节点最近一次健康检查是否成功，还没有检查过时返回 None
+pub fn upstream_up(chain: &str, network: &str, url: &str) -> Option<bool> {
+    let state = STATE.read().ok()?;
+    let up = state
+        .latest(chain, network)
+        .find(|(u, _)| u.url == url && !u.reference)
+        .map(|(_, check)| check.ok);
+    up
+}
+
+pub fn metrics() -> String {
+    STATE
+        .read()
+        .map(|state| state.metrics(registry::get()))
+        .unwrap_or_default()
+}
+
+pub fn status() -> Vec<UpstreamStatus> {
+    STATE.read().map(|state| state.status()).unwrap_or_default()
+}
+
+/// 需要检查的节点和检查方法
+pub fn targets(registry: &Registry) -> Vec<(Upstream, Probe)> {
+    let mut targets = Vec::new();
+    for chain in &registry.chains {
+        let networks = chain
+            .networks
+            .iter()
+            .filter(|n| n.status != NetworkStatus::Retired);
+        for network in networks {
+            let upstreams = &network.upstreams;
+            // 规则选择的节点池也由代理转发，同样需要检查
+            let pools = upstreams
+                .pools
+                .iter()
+                .flat_map(|(name, members)| labeled(name, members.iter().map(|u| &u.url)));
+            let urls = labeled("http", upstreams.http.iter().map(|u| &u.url))
+                .chain(labeled("archive", upstreams.archive.iter().map(|u| &u.url)))
+                .chain(pools)
+                .map(|(label, url)| (label, url, false));
+            let reference =
+                labeled("reference", &network.reference).map(|(label, url)| (label, url, true));
+            for (label, url, reference) in urls.chain(reference) {
+                let upstream = Upstream {
+                    chain: chain.name.clone(),
+                    network: network.name.clone(),
+                    url: url.clone(),
+                    label,
+                    reference,
+                };
+                targets.push((upstream, chain.probe));
+            }
+        }
+    }
+    targets
+}
+
+/// 按顺序给同一种节点编号
+fn labeled<'a>(
+    kind: &'a str,
+    urls: impl IntoIterator<Item = &'a String>,
+) -> impl Iterator<Item = (String, &'a String)> {
+    urls.into_iter()
+        .enumerate()
+        .map(move |(i, url)| (format!("{}-{}", kind, i), url))
+}
+
+/// 并发检查所有节点
+pub async fn check_all(
+    client: &reqwest::Client,
+    targets: Vec<(Upstream, Probe)>,
+) -> Vec<(Upstream, Check)> {
+    let tasks: Vec<_> = targets
+        .into_iter()
+        .map(|(upstream, probe)| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let check = check(&client, probe, &upstream.url).await;
+                (upstream, check)
+            })
+        })
+        .collect();
+    let mut checks = Vec::new();
+    for task in tasks {
+        match task.await {
+            Ok(check) => checks.push(check),
+            Err(e) => tracing::error!("health check task failed: {}", e),
+        }
+    }
+    checks
+}
+
+pub async fn check(client: &reqwest::Client, probe: Probe, url: &str) -> Check {
+    let start = Instant::now();
+    let result = call(client, probe, url).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+    match result {
+        Ok(height) => Check {
+            at: Utc::now(),
+            ok: true,
+            latency_ms,
+            height,
+            error: None,
+        },
+        Err(e) => Check {
+            at: Utc::now(),
+            ok: false,
+            latency_ms,
+            height: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// 调用节点并返回块高
+async fn call(client: &reqwest::Client, probe: Probe, url: &str) -> Result<Option<u64>> {
+    let response = match rpc_method(probe) {
+        Some(method) => {
+            let request = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": method,
+                "params": [],
+            });
+            client
+                .post(url)
+                .json(&request)
+                .send()
+                .await
+                .map_err(reqwest::Error::without_url)?
+        }
+        None => {
+            let url = format!("{}/v1", url.trim_end_matches('/'));
+            client
+                .get(url)
+                .send()
+                .await
+                .map_err(reqwest::Error::without_url)?
+        }
+    };
+    if !response.status().is_success() {
+        return Err(anyhow!("http status {}", response.status()));
+    }
+    let body: serde_json::Value = response.json().await.map_err(reqwest::Error::without_url)?;
+    if rpc_method(probe).is_none() {
+        return Ok(parse_height(&body["block_height"]));
+    }
+    if let Some(error) = body.get("error") {
+        return Err(anyhow!("rpc error {}", error));
+    }
+    let Some(result) = body.get("result") else {
+        return Err(anyhow!("rpc response without result"));
+    };
+    let height = match probe {
+        Probe::RpcDiscover => None,
+        Probe::NearStatus => parse_height(&result["sync_info"]["latest_block_height"]),
+        _ => parse_height(result),
+    };
+    Ok(height)
+}
+
+/// 块高可能是数字、十进制字符串或者 `0x` 开头的十六进制字符串
+fn parse_height(value: &serde_json::Value) -> Option<u64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64(),
+        serde_json::Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
+    }
+}
+
+/// json-rpc 的方法名，REST api 返回 None
+fn rpc_method(probe: Probe) -> Option<&'static str> {
+    match probe {
+        Probe::EthBlockNumber => Some("eth_blockNumber"),
+        Probe::StarknetBlockNumber => Some("starknet_blockNumber"),
+        Probe::RpcDiscover => Some("rpc.discover"),
+        Probe::SuiCheckpoint => Some("sui_getLatestCheckpointSequenceNumber"),
+        Probe::NearStatus => Some("status"),
+        Probe::AptosLedger => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::get, routing::post, Json, Router};
+
+    use super::*;
+
+    /// 在本地端口启动一个块高固定的假节点，返回它的地址
+    fn fake_node(height: u64) -> String {
+        let app = Router::new()
+            .route(
+                "/",
+                post(move |Json(request): Json<serde_json::Value>| async move {
+                    let body = match request["method"].as_str() {
+                        Some("eth_blockNumber") => serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": 1,
+                            "result": format!("0x{:x}", height),
+                        }),
+                        _ => serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "id": 1,
+                            "error": {"code": -32601, "message": "method not found"},
+                        }),
+                    };
+                    Json(body)
+                }),
+            )
+            .route(
+                "/v1",
+                get(move || async move {
+                    Json(serde_json::json!({"block_height": height.to_string()}))
+                }),
+            );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_check_all() {
+        let node = fake_node(100);
+        let behind = fake_node(80);
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let registry = Registry::parse(&format!(
+            r#"
+            [[chains]]
+            name = "Ethereum"
+            examples = "ethereum"
+            [[chains.networks]]
+            name = "Mainnet"
+            path = "ethereum"
+            upstreams = {{ http = ["{node}", "{behind}", "{closed}"], pools = {{ trace = ["{behind}"] }} }}
+
+            [[chains]]
+            name = "Aptos"
+            examples = "aptos"
+            probe = "aptos_ledger"
+            [[chains.networks]]
+            name = "Mainnet"
+            path = "aptos"
+            upstreams = {{ http = ["{behind}"] }}
+            reference = "{node}"
+
+            [[chains]]
+            name = "Near"
+            examples = "near"
+            probe = "near_status"
+            [[chains.networks]]
+            name = "Mainnet"
+            path = "near"
+            upstreams = {{ http = ["{node}"] }}
+            "#
+        ))
+        .unwrap();
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(2))
+            .build()
+            .unwrap();
+        let mut state = HealthState::default();
+        for (upstream, check) in check_all(&client, targets(&registry)).await {
+            state.record(upstream, check);
+        }
+        // 有一个节点跟上了最高块，没有降级
+        let ethereum = state.network("Ethereum", "Mainnet", 10);
+        assert_eq!(ethereum.up, Some(true));
+        assert_eq!(ethereum.lag, Some(0));
+        assert!(!ethereum.degraded);
+        // 唯一的节点比参照节点落后 20 块
+        let aptos = state.network("Aptos", "Mainnet", 10);
+        assert_eq!(aptos.up, Some(true));
+        assert_eq!(aptos.lag, Some(20));
+        assert!(aptos.degraded);
+        // 假节点不支持 NEAR 的 status
+        assert_eq!(state.network("Near", "Mainnet", 10).up, Some(false));
+        assert_eq!(
+            state.network("Sui", "Mainnet", 10),
+            NetworkHealth::default()
+        );
+
+        let status = state.status();
+        assert_eq!(status.len(), 7);
+        // 规则选择的节点池也检查
+        let trace = status
+            .iter()
+            .find(|s| s.upstream.label == "trace-0")
+            .unwrap();
+        assert_eq!(trace.height, Some(80));
+        let down = status.iter().find(|s| s.upstream.url == closed).unwrap();
+        assert!(!down.up);
+        assert!(down.history[0].error.is_some());
+        assert_eq!(down.uptime, 0.0);
+        let lagging = status
+            .iter()
+            .find(|s| s.upstream.chain == "Ethereum" && s.upstream.label == "http-1")
+            .unwrap();
+        assert_eq!((lagging.height, lagging.lag), (Some(80), Some(20)));
+
+        // 状态和指标中只有节点的名字，没有地址
+        let json = serde_json::to_string(&status).unwrap();
+        assert!(!json.contains(&node) && json.contains("\"label\":\"http-2\""));
+        let metrics = state.metrics(&registry);
+        assert!(!metrics.contains(&node));
+        assert!(metrics.contains("node_network_degraded{chain=\"Aptos\",network=\"Mainnet\"} 1"));
+        assert!(
+            metrics.contains("node_network_lag_blocks{chain=\"Ethereum\",network=\"Mainnet\"} 0")
+        );
+    }
+
+    #[test]
+    fn test_parse_height() {
+        assert_eq!(parse_height(&serde_json::json!("0x1b4")), Some(436));
+        assert_eq!(parse_height(&serde_json::json!("436")), Some(436));
+        assert_eq!(parse_height(&serde_json::json!(436)), Some(436));
+        assert_eq!(parse_height(&serde_json::json!(null)), None);
+    }
+}
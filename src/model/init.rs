@@ -1,6 +1,29 @@
-use crate::model::{db, log_parse};
+use crate::model::{anomaly, app, billing, db, health, log_parse, registry, usage};
 
 pub async fn init() {
+    init_env();
+    registry::init().expect("Failed to load chain registry");
+    billing::init().expect("Failed to load compute units");
+    db::init().await.expect("Failed to connect to database");
+    db::migrate().await.expect("Failed to migrate database");
+    app::load_keys().await.expect("Failed to load app keys");
+    usage::store::init()
+        .await
+        .expect("Failed to init usage store");
+    log_parse::cache::init().await.expect("Failed to cache log");
+    anomaly::init();
+    billing::watch();
+    health::init();
+}
+
+/// 子命令只需要环境变量、日志和数据库，不需要启动日志缓存
+pub async fn init_command() {
+    init_env();
+    db::init().await.expect("Failed to connect to database");
+    db::migrate().await.expect("Failed to migrate database");
+}
+
+fn init_env() {
     dotenvy::dotenv().ok();
 
     let file_appender = tracing_appender::rolling::daily("log", "node-service.log");
@@ -11,7 +34,4 @@ pub async fn init() {
         .with_line_number(true)
         .init();
     tracing::info!("log init finished");
-
-    db::init().await.expect("Failed to connect to database");
-    log_parse::cache::init().await.expect("Failed to cache log");
 }
@@ -1,70 +1,163 @@
-use std::{cell::RefCell, sync::Mutex};
+use std::{
+    sync::{mpsc, RwLock},
+    time::Duration,
+};
 
-use super::log::Log;
+use super::{
+    log::Log,
+    source::{self, LogSource},
+    stats::ParseStats,
+};
+use crate::model::{anomaly, usage};
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use once_cell::sync::OnceCell;
+use tokio::sync::broadcast;
 
-static CACHE: OnceCell<Mutex<RefCell<LogCache>>> = OnceCell::new();
+static CACHE: OnceCell<RwLock<LogCache>> = OnceCell::new();
+static INGESTED: OnceCell<broadcast::Sender<Log>> = OnceCell::new();
+/// 代理产生的日志先进入这个队列，由读取线程和其它来源的日志一起写入缓存
+static PUSHED: OnceCell<mpsc::Sender<Log>> = OnceCell::new();
 
-pub async fn init() -> std::result::Result<(), Mutex<RefCell<LogCache>>> {
-    let cache = match LogCache::new() {
-        Ok(cache) => cache,
-        Err(e) => {
-            tracing::error!("cache log failed: {}", e);
-            LogCache::default()
+/// 内存中只保留最近 8 天的日志，足够计算 7 天的统计
+const RETENTION_DAYS: i64 = 8;
+
+pub async fn init() -> Result<()> {
+    if CACHE.get().is_some() {
+        return Ok(());
+    }
+    let mut cache = LogCache::default();
+    // 来源配置错误时仍然启动读取线程，代理的日志不受影响
+    let mut sources = source::from_env().unwrap_or_else(|e| {
+        tracing::error!("cache log failed: {}", e);
+        Vec::new()
+    });
+    cache.poll(&mut sources);
+    // 已经初始化过了，不再重复启动读取线程
+    if CACHE.set(RwLock::new(cache)).is_err() {
+        return Ok(());
+    }
+    let (sender, pushed) = mpsc::channel();
+    let _ = PUSHED.set(sender);
+    // 后台线程持有所有日志来源，每秒读取新到达的日志
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let result = LogCache::write(|cache| {
+            cache.ingest(pushed.try_iter().collect());
+            cache.poll(&mut sources);
+            Ok(())
+        });
+        if let Err(e) = result {
+            tracing::error!("refresh log cache failed: {}", e);
         }
+    });
+    Ok(())
+}
+
+/// 代理在进程内产生的日志，不在请求中拿缓存的写锁，一秒内由读取线程写入缓存，
+/// 和从日志来源读取的日志走同一个流程
+pub fn push(log: Log) {
+    let Some(sender) = PUSHED.get() else {
+        tracing::error!("ingest proxy log failed: log cache not initialized");
+        return;
     };
-    CACHE.set(Mutex::new(RefCell::new(cache)))
+    if sender.send(log).is_err() {
+        tracing::error!("ingest proxy log failed: log cache stopped");
+    }
 }
 
-#[derive(Debug, Clone)]
+/// 订阅新解析到的日志
+pub fn subscribe() -> broadcast::Receiver<Log> {
+    ingested().subscribe()
+}
+
+fn ingested() -> &'static broadcast::Sender<Log> {
+    INGESTED.get_or_init(|| broadcast::channel(4096).0)
+}
+
+#[derive(Debug)]
 pub struct LogCache {
     pub data: Vec<Log>,
     pub latest_time: DateTime<Utc>,
+    pub stats: ParseStats,
 }
 
 impl LogCache {
-    pub fn new() -> Result<Self> {
-        let logs = Log::parse_file(&Self::get_log_path()?)?;
-        Ok(Self {
-            data: logs,
-            latest_time: Utc::now(),
-        })
+    /// 在读锁下访问缓存的日志，避免复制整个日志列表
+    pub fn read<T>(f: impl FnOnce(&LogCache) -> T) -> Result<T> {
+        let cache = CACHE
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("Log cache not initialized"))?;
+        match cache.read() {
+            Ok(cache) => Ok(f(&cache)),
+            Err(e) => {
+                tracing::error!("cache log failed: {}", e);
+                Err(anyhow::anyhow!("cache log failed: {}", e))
+            }
+        }
     }
 
-    fn get_logs() -> Result<Vec<Log>> {
-        let logs = Log::parse_file(&Self::get_log_path()?)?;
-        Ok(logs)
+    fn write<T>(f: impl FnOnce(&mut LogCache) -> Result<T>) -> Result<T> {
+        let cache = CACHE
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("Log cache not initialized"))?;
+        match cache.write() {
+            Ok(mut cache) => f(&mut cache),
+            Err(e) => {
+                tracing::error!("cache log failed: {}", e);
+                Err(anyhow::anyhow!("cache log failed: {}", e))
+            }
+        }
     }
 
-    fn get_log_path() -> Result<String> {
-        std::env::var("PARSE_LOG_FILE").map_err(|_| anyhow::anyhow!("PARSE_LOG_FILE must be set"))
+    fn poll(&mut self, sources: &mut [Box<dyn LogSource>]) {
+        for source in sources.iter_mut() {
+            // 坏行只计数并保留样本，不影响其它日志
+            match source.poll(&mut self.stats) {
+                Ok(logs) => self.ingest(logs),
+                Err(e) => tracing::error!("read logs of {} failed: {}", source.node(), e),
+            }
+        }
+        self.evict();
+        self.latest_time = Utc::now();
     }
 
-    pub async fn get() -> Result<Self> {
-        match CACHE.get() {
-            Some(cache) => {
-                let cache = match cache.lock() {
-                    Ok(cache) => cache,
-                    Err(e) => {
-                        tracing::error!("cache log failed: {}", e);
-                        return Err(anyhow::anyhow!("cache log failed: {}", e));
-                    }
-                };
-                let mut cache = cache.borrow_mut();
-                if Utc::now()
-                    .signed_duration_since(cache.latest_time)
-                    .num_seconds()
-                    > 1
-                {
-                    // tracing::info!("cache expired, update cache");
-                    cache.data = Self::get_logs()?;
-                    cache.latest_time = Utc::now();
-                }
-                Ok(cache.clone())
+    /// 把新日志加入缓存，同时交给持久化存储、异常检测和实时订阅者
+    fn ingest(&mut self, mut logs: Vec<Log>) {
+        let sender = ingested();
+        for log in &logs {
+            usage::record(log);
+            anomaly::record(log);
+            if sender.receiver_count() > 0 {
+                let _ = sender.send(log.clone());
             }
-            None => Err(anyhow::anyhow!("Log cache not initialized")),
+        }
+        // 多个来源的日志交错到达，保持按时间排序，淘汰旧日志依赖这一点。
+        // 晚到的日志只比最新的日志早一点，只需要和末尾的一小段合并
+        logs.sort_by_key(|log| log.msec);
+        let Some(first) = logs.first().map(|log| log.msec) else {
+            return;
+        };
+        let start = self.data.partition_point(|log| log.msec <= first);
+        if start == self.data.len() {
+            self.data.append(&mut logs);
+            return;
+        }
+        let mut tail = self.data.split_off(start);
+        tail.append(&mut logs);
+        tail.sort_by_key(|log| log.msec);
+        self.data.append(&mut tail);
+    }
+
+    /// 以最新一条日志的时间为准淘汰过旧的日志
+    fn evict(&mut self) {
+        let Some(latest) = self.data.last().map(|log| log.msec) else {
+            return;
+        };
+        let cutoff = latest - chrono::Duration::days(RETENTION_DAYS);
+        let expired = self.data.partition_point(|log| log.msec < cutoff);
+        if expired > 0 {
+            self.data.drain(..expired);
         }
     }
 }
@@ -74,6 +167,30 @@ impl Default for LogCache {
         Self {
             data: Vec::new(),
             latest_time: Utc::now(),
+            stats: ParseStats::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_ingest_out_of_order() {
+        let log = |secs| Log {
+            msec: Utc.timestamp_opt(secs, 0).unwrap(),
+            ..Default::default()
+        };
+        let mut cache = LogCache::default();
+        cache.ingest(vec![log(1), log(5), log(3)]);
+        cache.ingest(vec![log(6), log(7)]);
+        // 晚到的日志合并进末尾
+        cache.ingest(vec![log(4), log(2)]);
+        cache.ingest(vec![]);
+        let secs: Vec<i64> = cache.data.iter().map(|l| l.msec.timestamp()).collect();
+        assert_eq!(secs, vec![1, 2, 3, 4, 5, 6, 7]);
+    }
+}
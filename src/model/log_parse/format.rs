@@ -0,0 +1,293 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Deserialize;
+
+use super::{
+    log::{optional, Log, Scheme},
+    stats::{LineError, SkipReason},
+};
+
+/// 支持的访问日志格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// nginx.conf 中定义的 `json_analytics`
+    NginxJson,
+    /// nginx 默认的 `combined`
+    NginxCombined,
+    /// Caddy 的 json 访问日志
+    CaddyJson,
+}
+
+impl LogFormat {
+    pub fn parse(&self, line: &str) -> Result<Log, LineError> {
+        if line.trim().is_empty() {
+            return Err(LineError::new(SkipReason::Empty, "empty line"));
+        }
+        match self {
+            LogFormat::NginxJson => Log::parse(line),
+            LogFormat::NginxCombined => parse_combined(line),
+            LogFormat::CaddyJson => parse_caddy(line),
+        }
+    }
+
+    /// 日志中是否有真正的 request_id。其它格式的 request_id 是整行的 md5，
+    /// 同一秒内完全相同的请求会得到同样的 id，不能用来去重
+    pub fn has_request_id(&self) -> bool {
+        *self == LogFormat::NginxJson
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::NginxJson => write!(f, "nginx_json"),
+            LogFormat::NginxCombined => write!(f, "nginx_combined"),
+            LogFormat::CaddyJson => write!(f, "caddy_json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nginx_json" | "json_analytics" => Ok(LogFormat::NginxJson),
+            "nginx_combined" | "combined" => Ok(LogFormat::NginxCombined),
+            "caddy_json" | "caddy" => Ok(LogFormat::CaddyJson),
+            _ => Err(format!("{} is not a valid log format", s)),
+        }
+    }
+}
+
+/// 没有 request_id 的格式用整行的 md5 代替，只用来标识日志，不用于去重
+fn line_id(line: &str) -> String {
+    format!("{:x}", md5::compute(line))
+}
+
+/// `$remote_addr - $remote_user [$time_local] "$request" $status $body_bytes_sent "$http_referer" "$http_user_agent"`
+fn parse_combined(line: &str) -> Result<Log, LineError> {
+    let fields = split_combined(line)
+        .ok_or_else(|| LineError::new(SkipReason::Truncated, "unterminated field"))?;
+    if fields.len() < 7 {
+        return Err(LineError::new(
+            SkipReason::InvalidField,
+            format!("expect at least 7 fields, got {}", fields.len()),
+        ));
+    }
+    let time = DateTime::parse_from_str(&fields[3], "%d/%b/%Y:%H:%M:%S %z").map_err(|e| {
+        LineError::new(
+            SkipReason::InvalidField,
+            format!("time_local invalid: {}", e),
+        )
+    })?;
+    let status = fields[5].parse().map_err(|_| {
+        LineError::new(
+            SkipReason::InvalidField,
+            format!("status invalid: {}", fields[5]),
+        )
+    })?;
+    let mut request = fields[4].splitn(3, ' ');
+    let method = request.next().unwrap_or_default();
+    let uri = request.next().unwrap_or_default();
+    let protocol = request.next().unwrap_or_default();
+    Ok(Log {
+        msec: time.with_timezone(&Utc),
+        request_id: line_id(line),
+        remote_addr: fields[0].clone(),
+        remote_user: optional(fields[2].clone()),
+        time_local: fields[3].clone(),
+        time_iso8601: Some(time),
+        request: fields[4].clone(),
+        request_uri: uri.to_string(),
+        args: uri.split_once('?').map(|(_, args)| args.to_string()),
+        status,
+        body_bytes_sent: fields[6].parse().ok(),
+        http_referer: fields.get(7).cloned().and_then(optional),
+        http_user_agent: fields.get(8).cloned().and_then(optional),
+        server_protocol: protocol.to_string(),
+        request_method: method.parse().unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+/// 按空格切分，双引号和方括号内的空格不切分，引号没有闭合时返回 None
+fn split_combined(line: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+        let Some(&c) = chars.peek() else {
+            break;
+        };
+        let mut field = String::new();
+        match c {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        '\\' => field.push(chars.next()?),
+                        '"' => break,
+                        ch => field.push(ch),
+                    }
+                }
+            }
+            '[' => {
+                chars.next();
+                loop {
+                    match chars.next()? {
+                        ']' => break,
+                        ch => field.push(ch),
+                    }
+                }
+            }
+            _ => {
+                while let Some(&ch) = chars.peek() {
+                    if ch == ' ' {
+                        break;
+                    }
+                    field.push(ch);
+                    chars.next();
+                }
+            }
+        }
+        fields.push(field);
+    }
+    Some(fields)
+}
+
+#[derive(Deserialize)]
+struct CaddyLog {
+    ts: f64,
+    request: CaddyRequest,
+    #[serde(default)]
+    duration: Option<f64>,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    bytes_read: Option<u64>,
+    status: u16,
+}
+
+#[derive(Deserialize)]
+struct CaddyRequest {
+    #[serde(default)]
+    remote_ip: String,
+    #[serde(default)]
+    remote_port: String,
+    #[serde(default)]
+    client_ip: String,
+    #[serde(default)]
+    proto: String,
+    method: String,
+    #[serde(default)]
+    host: String,
+    uri: String,
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    tls: Option<CaddyTls>,
+}
+
+#[derive(Deserialize)]
+struct CaddyTls {
+    #[serde(default)]
+    server_name: String,
+}
+
+fn parse_caddy(line: &str) -> Result<Log, LineError> {
+    let caddy: CaddyLog = serde_json::from_str(line)?;
+    let msec = Utc
+        .timestamp_millis_opt((caddy.ts * 1000.0) as i64)
+        .single()
+        .ok_or_else(|| {
+            LineError::new(
+                SkipReason::InvalidField,
+                format!("ts invalid: {}", caddy.ts),
+            )
+        })?;
+    let request = caddy.request;
+    let header = |name: &str| request.headers.get(name).and_then(|v| v.first()).cloned();
+    let remote_addr = if request.client_ip.is_empty() {
+        request.remote_ip.clone()
+    } else {
+        request.client_ip.clone()
+    };
+    Ok(Log {
+        msec,
+        request_id: line_id(line),
+        request_length: caddy.bytes_read,
+        remote_addr,
+        remote_port: request.remote_port.parse().ok(),
+        time_local: msec.format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+        time_iso8601: Some(msec.into()),
+        request: format!("{} {} {}", request.method, request.uri, request.proto),
+        args: request
+            .uri
+            .split_once('?')
+            .map(|(_, args)| args.to_string()),
+        status: caddy.status,
+        body_bytes_sent: caddy.size,
+        bytes_sent: caddy.size,
+        http_referer: header("Referer"),
+        http_user_agent: header("User-Agent"),
+        http_x_forwarded_for: header("X-Forwarded-For"),
+        http_host: Some(request.host.clone()).filter(|h| !h.is_empty()),
+        server_name: request
+            .tls
+            .as_ref()
+            .map(|t| t.server_name.clone())
+            .unwrap_or_else(|| request.host.clone()),
+        request_time: caddy.duration,
+        scheme: if request.tls.is_some() {
+            Scheme::Https
+        } else {
+            Scheme::Http
+        },
+        request_method: request.method.parse().unwrap_or_default(),
+        server_protocol: request.proto.clone(),
+        request_uri: request.uri,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::log_parse::log::Method;
+
+    #[test]
+    fn test_parse_combined() {
+        let line = r#"172.23.0.1 - - [01/Dec/2022:02:37:47 +0800] "POST /ethereum/abc?x=1 HTTP/1.1" 200 615 "-" "curl/7.86.0""#;
+        let log = LogFormat::NginxCombined.parse(line).unwrap();
+        assert_eq!(log.msec.timestamp(), 1669833467);
+        assert_eq!(log.request_uri, "/ethereum/abc?x=1");
+        assert_eq!(log.args.as_deref(), Some("x=1"));
+        assert_eq!(log.request_method, Method::Post);
+        assert_eq!(log.status, 200);
+        assert_eq!(log.body_bytes_sent, Some(615));
+        assert_eq!(log.http_referer, None);
+        assert_eq!(log.http_user_agent.as_deref(), Some("curl/7.86.0"));
+
+        let e = LogFormat::NginxCombined
+            .parse(r#"172.23.0.1 - - [01/Dec/2022:02:37:47 +0800] "POST /eth"#)
+            .unwrap_err();
+        assert_eq!(e.reason, SkipReason::Truncated);
+    }
+
+    #[test]
+    fn test_parse_caddy() {
+        let line = r#"{"level":"info","ts":1669862267.429,"logger":"http.log.access","msg":"handled request","request":{"remote_ip":"127.0.0.1","remote_port":"41342","client_ip":"10.0.0.1","proto":"HTTP/2.0","method":"POST","host":"rpc.example.com","uri":"/sui/abc","headers":{"User-Agent":["curl/7.82.0"]},"tls":{"resumed":false,"version":772,"cipher_suite":4865,"proto":"h2","server_name":"rpc.example.com"}},"bytes_read":64,"user_id":"","duration":0.0125,"size":120,"status":200,"resp_headers":{}}"#;
+        let log = LogFormat::CaddyJson.parse(line).unwrap();
+        assert_eq!(log.msec.timestamp_millis(), 1669862267429);
+        assert_eq!(log.remote_addr, "10.0.0.1");
+        assert_eq!(log.request_uri, "/sui/abc");
+        assert_eq!(log.scheme, Scheme::Https);
+        assert_eq!(log.request_time, Some(0.0125));
+        assert_eq!(log.bytes_sent, Some(120));
+        assert_eq!(log.http_user_agent.as_deref(), Some("curl/7.82.0"));
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use serde::Serialize;
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+
+use super::{cache, log::Log, route::Route};
+
+/// 某个 app 在一秒内的请求统计
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct LiveStats {
+    pub timestamp: i64,
+    pub requests: u64,
+    pub errors: u64,
+    pub avg_latency_ms: f64,
+    pub max_latency_ms: f64,
+    #[serde(skip)]
+    total_latency_ms: f64,
+    /// 有 request_time 的请求数，平均延迟只算这些请求
+    #[serde(skip)]
+    timed: u64,
+}
+
+impl LiveStats {
+    pub fn add(&mut self, log: &Log) {
+        self.requests += 1;
+        if log.is_error() {
+            self.errors += 1;
+        }
+        if let Some(t) = log.request_time {
+            let ms = t * 1000.0;
+            self.total_latency_ms += ms;
+            self.timed += 1;
+            self.max_latency_ms = self.max_latency_ms.max(ms);
+        }
+    }
+
+    fn finish(&mut self) -> Self {
+        let mut stats = std::mem::take(self);
+        stats.timestamp = Utc::now().timestamp();
+        if stats.timed > 0 {
+            stats.avg_latency_ms = stats.total_latency_ms / stats.timed as f64;
+        }
+        stats
+    }
+}
+
+/// 订阅某个 api key 的实时统计，每秒产出一条，接收端关闭后后台任务自动退出
+pub fn subscribe(api_key: &str) -> mpsc::Receiver<LiveStats> {
+    let mut logs = cache::subscribe();
+    let (tx, rx) = mpsc::channel(16);
+    let api_key = api_key.to_string();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        let mut stats = LiveStats::default();
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    if tx.send(stats.finish()).await.is_err() {
+                        break;
+                    }
+                }
+                log = logs.recv() => match log {
+                    Ok(log) if Route::parse(&log.request_uri).is_some_and(|r| r.api_key == api_key) => {
+                        stats.add(&log)
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => tracing::warn!("live stats lagged, {} logs skipped", n),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    });
+    rx
+}
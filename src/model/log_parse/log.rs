@@ -1,10 +1,15 @@
-use std::io::BufRead;
+use std::{fmt, io::BufRead, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::stats::{LineError, ParseStats, SkipReason};
+use crate::model::registry::Tier;
+
+/// nginx `json_analytics` 格式输出的原始日志，所有字段都是字符串，缺失值为 "-"
 #[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Log {
+pub struct RawLog {
     pub msec: String,
     pub connection: String,
     pub connection_requests: String,
@@ -42,33 +47,383 @@ pub struct Log {
     pub pipe: String,
     pub gzip_ratio: String,
     pub http_cf_ray: String,
+    /// 逗号分隔的 JSON-RPC 方法名，旧的日志没有这个字段
+    #[serde(default)]
+    pub rpc_methods: String,
+    /// 更早的日志记录了完整的请求体，只用来取出方法名
+    #[serde(default)]
+    pub request_body: String,
+}
+
+/// 解析后的日志，从 [`RawLog`] 转换而来
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+#[serde(try_from = "RawLog")]
+pub struct Log {
+    /// 日志来自哪个节点，由日志来源填写
+    pub node: String,
+    pub msec: DateTime<Utc>,
+    pub connection: u64,
+    pub connection_requests: u64,
+    pub pid: u32,
+    pub request_id: String,
+    pub request_length: Option<u64>,
+    pub remote_addr: String,
+    pub remote_user: Option<String>,
+    pub remote_port: Option<u16>,
+    pub time_local: String,
+    pub time_iso8601: Option<DateTime<FixedOffset>>,
+    pub request: String,
+    pub request_uri: String,
+    pub args: Option<String>,
+    pub status: u16,
+    pub body_bytes_sent: Option<u64>,
+    pub bytes_sent: Option<u64>,
+    pub http_referer: Option<String>,
+    pub http_user_agent: Option<String>,
+    pub http_x_forwarded_for: Option<String>,
+    pub http_host: Option<String>,
+    pub server_name: String,
+    pub request_time: Option<f64>,
+    pub upstream: Option<String>,
+    pub upstream_connect_time: Option<f64>,
+    pub upstream_header_time: Option<f64>,
+    pub upstream_response_time: Option<f64>,
+    pub upstream_response_length: Option<u64>,
+    pub upstream_cache_status: Option<String>,
+    pub ssl_protocol: Option<String>,
+    pub ssl_cipher: Option<String>,
+    pub scheme: Scheme,
+    pub request_method: Method,
+    pub server_protocol: String,
+    pub pipe: bool,
+    pub gzip_ratio: Option<f64>,
+    pub http_cf_ray: Option<String>,
+    /// JSON-RPC 请求的方法名，批量请求按顺序包含每个方法，不是 JSON-RPC 请求时为空。
+    /// 请求体本身不保留，避免占用内存
+    pub rpc_methods: Vec<String>,
+    /// 代理转发时应用的节点类型，归档类型按倍数计费。nginx 等日志来源只能到达全节点，没有这个字段
+    #[serde(default)]
+    pub tier: Option<Tier>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Default)]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+    Other(String),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Default)]
+pub enum Method {
+    #[default]
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+    Other(String),
 }
 
 impl Log {
     pub fn new(line: &str) -> Result<Self> {
-        let log = match serde_json::from_str(line) {
+        let log = match Self::parse(line) {
             Ok(log) => log,
             Err(e) => {
-                tracing::error!("parse log failed: {}, line: {}", e, line);
-                return Err(anyhow::anyhow!(e));
+                tracing::error!("parse log failed: {}, line: {}", e.message, line);
+                return Err(anyhow::anyhow!(e.message));
             }
         };
         Ok(log)
     }
 
+    /// 解析一行日志，失败时给出原因，方便调用方分类统计
+    pub fn parse(line: &str) -> std::result::Result<Self, LineError> {
+        if line.trim().is_empty() {
+            return Err(LineError::new(SkipReason::Empty, "empty line"));
+        }
+        serde_json::from_str(line).map_err(LineError::from)
+    }
+
+    /// 解析整个文件，坏行会被跳过
     pub fn parse_file(path: &str) -> Result<Vec<Self>> {
+        Ok(Self::parse_file_with_stats(path)?.0)
+    }
+
+    /// 解析整个文件，同时返回坏行统计。
+    /// 最后一行如果没有换行符，说明 nginx 可能还在写，解析失败时记为 truncated
+    pub fn parse_file_with_stats(path: &str) -> Result<(Vec<Self>, ParseStats)> {
         let file = std::fs::File::open(path)?;
-        let reader = std::io::BufReader::new(file);
+        let mut reader = std::io::BufReader::new(file);
         let mut logs = Vec::new();
-        for line in reader.lines() {
-            let line = line?;
-            // 忽略空行和 '\x' 开头的机器人请求，因为他们会导致 serde 解析失败
-            if line.is_empty() || line.contains(r#" "request": "\x"#) {
-                continue;
+        let mut stats = ParseStats::default();
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+            let complete = buf.ends_with(b"\n");
+            let line = String::from_utf8_lossy(&buf);
+            let line = line.trim_end_matches(['\n', '\r']);
+            match Self::parse(line) {
+                Ok(log) => {
+                    stats.record_parsed();
+                    logs.push(log);
+                }
+                Err(mut e) => {
+                    if !complete {
+                        e.reason = SkipReason::Truncated;
+                    }
+                    stats.record_skipped(line, e);
+                }
             }
-            let log = Log::new(&line)?;
-            logs.push(log);
         }
-        Ok(logs)
+        Ok((logs, stats))
+    }
+
+    /// 请求时间的 unix 秒数，保留毫秒
+    pub fn epoch_secs(&self) -> f64 {
+        self.msec.timestamp_millis() as f64 / 1000.0
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.status >= 400
+    }
+}
+
+impl TryFrom<RawLog> for Log {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawLog) -> Result<Self> {
+        Ok(Self {
+            node: String::new(),
+            msec: parse_msec(&raw.msec)?,
+            connection: parse_required(&raw.connection, "connection")?,
+            connection_requests: parse_required(&raw.connection_requests, "connection_requests")?,
+            pid: parse_required(&raw.pid, "pid")?,
+            request_id: raw.request_id,
+            request_length: parse_optional(&raw.request_length),
+            remote_addr: raw.remote_addr,
+            remote_user: optional(raw.remote_user),
+            remote_port: parse_optional(&raw.remote_port),
+            time_iso8601: DateTime::parse_from_rfc3339(&raw.time_iso8601).ok(),
+            time_local: raw.time_local,
+            request: raw.request,
+            request_uri: raw.request_uri,
+            args: optional(raw.args),
+            status: parse_required(&raw.status, "status")?,
+            body_bytes_sent: parse_optional(&raw.body_bytes_sent),
+            bytes_sent: parse_optional(&raw.bytes_sent),
+            http_referer: optional(raw.http_referer),
+            http_user_agent: optional(raw.http_user_agent),
+            http_x_forwarded_for: optional(raw.http_x_forwarded_for),
+            http_host: optional(raw.http_host),
+            server_name: raw.server_name,
+            request_time: parse_optional(&raw.request_time),
+            upstream: optional(raw.upstream),
+            upstream_connect_time: parse_sum(&raw.upstream_connect_time),
+            upstream_header_time: parse_sum(&raw.upstream_header_time),
+            upstream_response_time: parse_sum(&raw.upstream_response_time),
+            upstream_response_length: parse_sum(&raw.upstream_response_length),
+            upstream_cache_status: optional(raw.upstream_cache_status),
+            ssl_protocol: optional(raw.ssl_protocol),
+            ssl_cipher: optional(raw.ssl_cipher),
+            scheme: raw.scheme.parse()?,
+            request_method: raw.request_method.parse()?,
+            server_protocol: raw.server_protocol,
+            pipe: raw.pipe == "p",
+            gzip_ratio: parse_optional(&raw.gzip_ratio),
+            http_cf_ray: optional(raw.http_cf_ray),
+            rpc_methods: match optional(raw.rpc_methods) {
+                Some(methods) => methods.split(',').map(|m| m.to_string()).collect(),
+                None => rpc_methods(&raw.request_body),
+            },
+            tier: None,
+        })
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "http" => Ok(Scheme::Http),
+            "https" => Ok(Scheme::Https),
+            _ => Ok(Scheme::Other(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scheme::Http => write!(f, "http"),
+            Scheme::Https => write!(f, "https"),
+            Scheme::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Ok(Method::Other(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Head => write!(f, "HEAD"),
+            Method::Post => write!(f, "POST"),
+            Method::Put => write!(f, "PUT"),
+            Method::Delete => write!(f, "DELETE"),
+            Method::Connect => write!(f, "CONNECT"),
+            Method::Options => write!(f, "OPTIONS"),
+            Method::Trace => write!(f, "TRACE"),
+            Method::Patch => write!(f, "PATCH"),
+            Method::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// 从 JSON-RPC 请求体中取出方法名，批量请求中缺少方法名的元素会被忽略
+pub fn rpc_methods(body: &str) -> Vec<String> {
+    let method = |call: &serde_json::Value| call.get("method")?.as_str().map(|m| m.to_string());
+    match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(calls)) => calls.iter().filter_map(method).collect(),
+        Ok(call) => method(&call).into_iter().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// "1669862267.429" 这样带毫秒的 unix 时间
+pub fn parse_msec(s: &str) -> Result<DateTime<Utc>> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, "0"));
+    let secs: i64 = secs.parse().map_err(|_| anyhow!("msec invalid: {}", s))?;
+    let millis: u32 = format!("{:0<3}", frac)
+        .get(..3)
+        .and_then(|m| m.parse().ok())
+        .ok_or_else(|| anyhow!("msec invalid: {}", s))?;
+    Utc.timestamp_opt(secs, millis * 1_000_000)
+        .single()
+        .ok_or_else(|| anyhow!("msec invalid: {}", s))
+}
+
+/// nginx 用 "-" 表示缺失的值
+pub fn optional(s: String) -> Option<String> {
+    if s == "-" || s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+fn parse_optional<T: FromStr>(s: &str) -> Option<T> {
+    s.trim().parse().ok()
+}
+
+fn parse_required<T: FromStr>(s: &str, field: &str) -> Result<T> {
+    s.trim()
+        .parse()
+        .map_err(|_| anyhow!("{} invalid: {}", field, s))
+}
+
+/// 请求被转发给多个 upstream 时，nginx 会输出 "0.001, 0.002" 这样的多个值，
+/// 内部跳转之间用 " : " 分隔，这里把它们加起来，"-" 不计入
+fn parse_sum<T: FromStr + std::iter::Sum<T>>(s: &str) -> Option<T> {
+    let values: Vec<T> = s
+        .split([',', ':'])
+        .filter_map(|v| v.trim().parse().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.into_iter().sum())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_msec() {
+        assert_eq!(
+            parse_msec("1669862267.429").unwrap().timestamp_millis(),
+            1669862267429
+        );
+        assert_eq!(
+            parse_msec("1669862267.4").unwrap().timestamp_millis(),
+            1669862267400
+        );
+        assert!(parse_msec("-").is_err());
+    }
+
+    #[test]
+    fn test_parse_sum() {
+        assert_eq!(parse_sum::<f64>("0.5, 0.25"), Some(0.75));
+        assert_eq!(parse_sum::<f64>("0.5, - : 0.25"), Some(0.75));
+        assert_eq!(parse_sum::<u64>("100, 20"), Some(120));
+        assert_eq!(parse_sum::<f64>("-"), None);
+    }
+
+    #[test]
+    fn test_rpc_methods() {
+        assert_eq!(
+            rpc_methods(r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId","params":[]}"#),
+            vec!["eth_chainId"]
+        );
+        assert_eq!(
+            rpc_methods(r#"[{"method":"eth_getLogs"},{"id":2},{"method":"eth_call"}]"#),
+            vec!["eth_getLogs", "eth_call"]
+        );
+        assert!(rpc_methods("-").is_empty());
+        assert!(rpc_methods("").is_empty());
+    }
+
+    #[test]
+    fn test_log_rpc_methods() {
+        let line = |extra: &str| {
+            format!(
+                r#"{{"msec": "1669862267.429", "connection": "1", "connection_requests": "1", "pid": "29", "request_id": "-", "request_length": "-", "remote_addr": "172.23.0.1", "remote_user": "-", "remote_port": "-", "time_local": "-", "time_iso8601": "-", "request": "POST /ethereum/key HTTP/1.1", "request_uri": "/ethereum/key", "args": "-", "status": "200", "body_bytes_sent": "-", "bytes_sent": "-", "http_referer": "-", "http_user_agent": "-", "http_x_forwarded_for": "-", "http_host": "-", "server_name": "-", "request_time": "-", "upstream": "-", "upstream_connect_time": "-", "upstream_header_time": "-", "upstream_response_time": "-", "upstream_response_length": "-", "upstream_cache_status": "-", "ssl_protocol": "-", "ssl_cipher": "-", "scheme": "http", "request_method": "POST", "server_protocol": "HTTP/1.1", "pipe": ".", "gzip_ratio": "-", "http_cf_ray": "-"{}}}"#,
+                extra
+            )
+        };
+        let parse = |extra: &str| {
+            serde_json::from_str::<Log>(&line(extra))
+                .unwrap()
+                .rpc_methods
+        };
+        assert_eq!(
+            parse(r#", "rpc_methods": "eth_getLogs,eth_call""#),
+            vec!["eth_getLogs", "eth_call"]
+        );
+        assert!(parse(r#", "rpc_methods": """#).is_empty());
+        // 更早的日志从请求体中取出方法名
+        assert_eq!(
+            parse(r#", "request_body": "{\"method\":\"eth_chainId\"}""#),
+            vec!["eth_chainId"]
+        );
+        assert!(parse("").is_empty());
     }
 }
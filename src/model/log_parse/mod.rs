@@ -1,3 +1,10 @@
 pub mod cache;
+pub mod format;
+pub mod live;
 pub mod log;
 pub mod query;
+pub mod route;
+pub mod source;
+pub mod stats;
+pub mod syslog;
+pub mod tail;
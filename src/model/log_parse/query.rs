@@ -1,93 +1,73 @@
 use anyhow::{Ok, Result};
-use chrono::Utc;
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
 
-use super::{cache::LogCache, log::Log};
+use crate::model::usage;
 
 pub struct QueryLog {
     pub date: String,
     pub query: String,
-    pub result: Vec<Log>,
+    pub total: i64,
 }
 
 impl QueryLog {
-    pub async fn query(query: &str) -> Result<Self> {
-        let cache = LogCache::get().await?;
-        let result = cache
-            .data
-            .into_iter()
-            .filter(|log| log.request_uri.contains(query))
-            .collect();
-        Ok(Self {
-            date: "all".to_string(),
-            query: query.to_string(),
-            result,
-        })
-    }
-
-    pub async fn query_status_200(query: &str) -> Result<Self> {
-        let cache = LogCache::get().await?;
-        let result = cache
-            .data
-            .into_iter()
-            .filter(|log| log.request_uri.contains(query) && log.status == "200")
-            .collect();
-        Ok(Self {
-            date: "all".to_string(),
-            query: query.to_string(),
-            result,
-        })
-    }
-
-    pub async fn query_today(query: &str) -> Result<Self> {
-        let today = Utc::now().format("%d/%b/%Y").to_string();
-        Self::query_with_date(query, &today).await
+    /// 按指定时区的今天统计
+    pub async fn query_today(query: &str, tz: Tz) -> Result<Self> {
+        Self::query_with_date(query, today(tz), tz).await
     }
 
-    pub async fn query_7days(query: &str) -> Result<Vec<Self>> {
+    pub async fn query_7days(query: &str, tz: Tz) -> Result<Vec<Self>> {
         let mut result = Vec::new();
-        let days = Self::get_7days()?;
+        let days = Self::get_7days(tz)?;
         for day in days {
-            let item = Self::query_with_date(query, &day).await?;
+            let item = Self::query_with_date(query, day, tz).await?;
             result.push(item);
         }
         Ok(result)
     }
 
-    async fn query_with_date(query: &str, date: &str) -> Result<Self> {
-        let cache = LogCache::get().await?;
-        let result: Vec<Log> = cache
-            .data
-            .into_iter()
-            .filter(|log| {
-                log.request_uri.contains(query)
-                    && log.time_local.contains(date)
-                    && log.status == "200"
-            })
-            .collect();
+    /// 今天用这个 api key 访问了其它链的请求，说明客户端配置错了
+    pub async fn query_chain_mismatch_today(api_key: &str, chain: &str, tz: Tz) -> Result<Self> {
+        let day = today(tz);
+        let total = usage::index::read(|index| index.day(api_key, day, tz).mismatch(chain))?;
         Ok(QueryLog {
-            date: date.to_string(),
+            date: day.format("%d/%b/%Y").to_string(),
+            query: api_key.to_string(),
+            total,
+        })
+    }
+
+    /// 按 api key 精确匹配某天成功的请求数，直接从用量索引中取，不扫描日志。
+    /// 日期按日志的时间戳和指定时区计算，与 nginx 所在的时区无关
+    async fn query_with_date(query: &str, day: NaiveDate, tz: Tz) -> Result<Self> {
+        let total = usage::index::read(|index| index.day(query, day, tz).total.ok)?;
+        Ok(QueryLog {
+            date: day.format("%d/%b/%Y").to_string(),
             query: query.to_string(),
-            result,
+            total,
         })
     }
 
-    fn get_7days() -> Result<Vec<String>> {
+    fn get_7days(tz: Tz) -> Result<Vec<NaiveDate>> {
         let mut days = Vec::new();
-        for i in 0..7 {
-            let day = Utc::now()
-                .checked_sub_signed(chrono::Duration::days(i))
-                .ok_or_else(|| anyhow::anyhow!("date time checked sub signed failed"))?
-                .format("%d/%b/%Y")
-                .to_string();
+        let mut day = today(tz);
+        for _ in 0..7 {
             days.push(day);
+            day = day
+                .pred_opt()
+                .ok_or_else(|| anyhow::anyhow!("date checked sub failed"))?;
         }
         Ok(days)
     }
 }
 
+fn today(tz: Tz) -> NaiveDate {
+    Utc::now().with_timezone(&tz).date_naive()
+}
+
 #[cfg(test)]
 mod test {
-    use crate::model::log_parse::cache;
+    use crate::model::log_parse::{log::Log, stats::SkipReason};
 
     use super::*;
 
@@ -95,7 +75,10 @@ mod test {
     fn test_log_parse() {
         let line = r#"{"msec": "1669862267.429", "connection": "1", "connection_requests": "1", "pid": "29", "request_id": "164d631b97ff62133da2add13fb9d849", "request_length": "732", "remote_addr": "172.23.0.1", "remote_user": "-", "remote_port": "55248", "time_local": "01/Dec/2022:02:37:47 +0000", "time_iso8601": "2022-12-01T02:37:47+00:00", "request": "GET / HTTP/1.1", "request_uri": "/", "args": "-", "status": "200", "body_bytes_sent": "615", "bytes_sent": "853", "http_referer": "-", "http_user_agent": "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36", "http_x_forwarded_for": "-", "http_host": "localhost:8000", "server_name": "localhost", "request_time": "0.000", "upstream": "-", "upstream_connect_time": "-", "upstream_header_time": "-", "upstream_response_time": "-", "upstream_response_length": "-", "upstream_cache_status": "-", "ssl_protocol": "-", "ssl_cipher": "-", "scheme": "http", "request_method": "GET", "server_protocol": "HTTP/1.1", "pipe": ".", "gzip_ratio": "-", "http_cf_ray": "-r#"}"#;
         let log = Log::new(line).unwrap();
-        assert_eq!(log.msec, "1669862267.429");
+        assert_eq!(log.msec.timestamp_millis(), 1669862267429);
+        assert_eq!(log.status, 200);
+        assert_eq!(log.request_time, Some(0.0));
+        assert_eq!(log.remote_user, None);
     }
 
     #[test]
@@ -104,56 +87,53 @@ mod test {
         assert_eq!(logs.len(), 8);
     }
 
-    async fn init_log_cache() {
-        cache::init().await.expect("Failed to cache log");
+    #[test]
+    fn test_log_parse_file_with_stats() {
+        let (logs, stats) = Log::parse_file_with_stats("src/model/test_data/access.log").unwrap();
+        assert_eq!(logs.len(), 8);
+        assert_eq!(stats.lines, 11);
+        assert_eq!(stats.skipped.get(&SkipReason::Empty), Some(&2));
+        // 最后一行没有换行符，按照还没写完处理
+        assert_eq!(stats.skipped.get(&SkipReason::Truncated), Some(&1));
+        assert_eq!(stats.samples.len(), 1);
     }
 
-    #[tokio::test]
-    async fn test_query_log() {
-        std::env::set_var("PARSE_LOG_FILE", "src/model/test_data/access.log");
-        init_log_cache().await;
-        let query_log = QueryLog::query("/").await.unwrap();
-        assert_eq!(query_log.result.len(), 8);
-        let query_log = QueryLog::query("ico").await.unwrap();
-        assert_eq!(query_log.result.len(), 4);
+    #[test]
+    fn test_log_parse_truncated_line() {
+        let line = r#"{"msec": "1669862267.429", "connection": "1""#;
+        let e = Log::parse(line).unwrap_err();
+        assert_eq!(e.reason, SkipReason::Truncated);
+        let e = Log::parse(r#"{"msec": "1669862267.429"}"#).unwrap_err();
+        assert_eq!(e.reason, SkipReason::InvalidField);
     }
 
-    #[tokio::test]
-    async fn test_query_log_with_status_200() {
-        std::env::set_var("PARSE_LOG_FILE", "src/model/test_data/access.log");
-        init_log_cache().await;
-        let query_log = QueryLog::query_status_200("/").await.unwrap();
-        assert_eq!(query_log.result.len(), 3);
-        let query_log = QueryLog::query_status_200("ico").await.unwrap();
-        assert_eq!(query_log.result.len(), 0);
+    /// 今天的请求记入用量索引后按 api key 查询
+    fn record_now(api_key: &str, status: u16) {
+        usage::index::record(&Log {
+            msec: Utc::now(),
+            request_uri: format!("/ethereum/{}", api_key),
+            status,
+            ..Default::default()
+        });
     }
 
     #[tokio::test]
-    #[ignore]
-    // 该测试与日期有关，今天测试能通过，明天就不行了，所以忽略
     async fn test_query_log_query_today() {
-        std::env::set_var("PARSE_LOG_FILE", "src/model/test_data/access.log");
-        init_log_cache().await;
-        let query_log = QueryLog::query_today("/").await.unwrap();
-        assert_eq!(query_log.result.len(), 3);
-        let query_log = QueryLog::query_today("ico").await.unwrap();
-        assert_eq!(query_log.result.len(), 2);
+        record_now("query0today", 200);
+        record_now("query0today", 200);
+        record_now("query0today", 500);
+        let query_log = QueryLog::query_today("query0today", Tz::UTC).await.unwrap();
+        assert_eq!(query_log.total, 2);
+        let query_log = QueryLog::query_today("query0other", Tz::UTC).await.unwrap();
+        assert_eq!(query_log.total, 0);
     }
 
     #[tokio::test]
-    #[ignore]
-    // 该测试与日期有关，今天测试能通过，明天就不行了，所以忽略
     async fn test_query_log_query_7days() {
-        std::env::set_var("PARSE_LOG_FILE", "src/model/test_data/access.log");
-        init_log_cache().await;
-        let query_logs = QueryLog::query_7days("/").await.unwrap();
+        record_now("query07days", 200);
+        let query_logs = QueryLog::query_7days("query07days", Tz::UTC).await.unwrap();
         assert_eq!(query_logs.len(), 7);
-        assert_eq!(query_logs[0].result.len(), 3);
-        assert_eq!(query_logs[1].result.len(), 0);
-        assert_eq!(query_logs[2].result.len(), 0);
-        assert_eq!(query_logs[3].result.len(), 0);
-        assert_eq!(query_logs[4].result.len(), 0);
-        assert_eq!(query_logs[5].result.len(), 0);
-        assert_eq!(query_logs[6].result.len(), 0);
+        let totals: Vec<i64> = query_logs.iter().map(|q| q.total).collect();
+        assert_eq!(totals, vec![1, 0, 0, 0, 0, 0, 0]);
     }
 }
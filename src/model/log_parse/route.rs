@@ -0,0 +1,103 @@
+use serde::Serialize;
+
+use crate::model::registry;
+
+/// 客户端访问节点的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Transport {
+    Http,
+    WebSocket,
+}
+
+/// 从请求路径中解析出来的链、api key 和访问方式
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Route {
+    /// 注册表中的链名和网络名
+    pub chain: String,
+    pub network: String,
+    pub api_key: String,
+    pub transport: Transport,
+}
+
+/// 查询参数中可以携带 api key 的名字
+pub const KEY_PARAMS: [&str; 3] = ["apikey", "api_key", "key"];
+
+impl Route {
+    /// 解析 `/ethereum/<key>`、`/ethereum-ws/<key>`、`/ethereum-goerli/<key>`、`/aptos/<key>/v1/...`
+    /// 以及 `/ethereum?apikey=<key>` 这样的路径，查询参数中的 key 优先，不是节点请求时返回 None
+    pub fn parse(uri: &str) -> Option<Self> {
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let mut segments = path.trim_start_matches('/').split('/');
+        let prefix = segments.next()?;
+        let (prefix, transport) = match prefix.strip_suffix("-ws") {
+            Some(p) => (p, Transport::WebSocket),
+            None => (prefix, Transport::Http),
+        };
+        let (chain, network) = registry::get().by_path(prefix)?;
+        // `/aptos/v1/accounts?api_key=<key>` 的第一段是 REST 路径，查询参数中有 key 时优先使用它
+        let param = query.split('&').find_map(|pair| {
+            let (name, value) = pair.split_once('=')?;
+            let is_key = KEY_PARAMS.contains(&name.to_lowercase().as_str());
+            (is_key && is_valid_key(value)).then_some(value)
+        });
+        let api_key = param.or_else(|| segments.next().filter(|segment| is_valid_key(segment)))?;
+        Some(Self {
+            chain: chain.name.clone(),
+            network: network.name.clone(),
+            api_key: api_key.to_string(),
+            transport,
+        })
+    }
+}
+
+/// api key 是 md5 的十六进制字符串，这里放宽到字母数字，避免把其它路径当成 key
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= 64 && key.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route_parse() {
+        let route = Route::parse("/ethereum/0123abcd").unwrap();
+        assert_eq!(route.chain, "Ethereum");
+        assert_eq!(route.network, "Mainnet");
+        assert_eq!(route.api_key, "0123abcd");
+        assert_eq!(route.transport, Transport::Http);
+
+        let route = Route::parse("/bsc-ws/0123abcd").unwrap();
+        assert_eq!(route.chain, "Bsc");
+        assert_eq!(route.transport, Transport::WebSocket);
+
+        let route = Route::parse("/starknet?foo=1&apikey=0123abcd").unwrap();
+        assert_eq!(route.chain, "StarkWare");
+        assert_eq!(route.api_key, "0123abcd");
+
+        let route = Route::parse("/ethereum-goerli-ws/0123abcd").unwrap();
+        assert_eq!(route.chain, "Ethereum");
+        assert_eq!(route.network, "Testnet - Goerli");
+        assert_eq!(route.transport, Transport::WebSocket);
+
+        let route = Route::parse("/aptos/0123abcd/v1/accounts").unwrap();
+        assert_eq!(route.api_key, "0123abcd");
+
+        let route = Route::parse("/aptos/v1/accounts?api_key=0123abcd").unwrap();
+        assert_eq!(route.chain, "Aptos");
+        assert_eq!(route.api_key, "0123abcd");
+        // 无效的查询参数不影响路径中的 key
+        let route = Route::parse("/ethereum/0123abcd?key=../x").unwrap();
+        assert_eq!(route.api_key, "0123abcd");
+    }
+
+    #[test]
+    fn test_route_parse_not_node_request() {
+        assert_eq!(Route::parse("/"), None);
+        assert_eq!(Route::parse("/favicon.ico"), None);
+        assert_eq!(Route::parse("/ethereum"), None);
+        assert_eq!(Route::parse("/unknown/0123abcd"), None);
+        assert_eq!(Route::parse("/ethereum/../etc"), None);
+        assert_eq!(Route::parse("/ethereum?referer=/ethereum/0123abcd"), None);
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+use super::{format::LogFormat, log::Log, stats::ParseStats, syslog::SyslogSource, tail::Tailer};
+
+/// 只配置了 PARSE_LOG_FILE 时使用的节点名
+pub const DEFAULT_NODE: &str = "access_log";
+
+/// 访问日志的来源，每个来源属于一个节点，读到的日志会合并到同一个用量流中
+pub trait LogSource: Send {
+    fn node(&self) -> &str;
+
+    /// 读取上次调用以来新到达的日志，坏行计入 stats
+    fn poll(&mut self, stats: &mut ParseStats) -> Result<Vec<Log>>;
+}
+
+/// 增量读取本地文件
+pub struct FileSource {
+    node: String,
+    format: LogFormat,
+    tailer: Tailer,
+}
+
+impl FileSource {
+    pub fn new(node: &str, format: LogFormat, path: impl Into<PathBuf>) -> Self {
+        Self {
+            node: node.to_string(),
+            format,
+            tailer: Tailer::new(path),
+        }
+    }
+}
+
+impl LogSource for FileSource {
+    fn node(&self) -> &str {
+        &self.node
+    }
+
+    fn poll(&mut self, stats: &mut ParseStats) -> Result<Vec<Log>> {
+        let lines = self.tailer.poll()?;
+        Ok(parse_lines(&self.node, self.format, lines, stats))
+    }
+}
+
+/// 按指定格式解析多行日志并打上节点名，坏行只计数不中断
+pub fn parse_lines(
+    node: &str,
+    format: LogFormat,
+    lines: impl IntoIterator<Item = String>,
+    stats: &mut ParseStats,
+) -> Vec<Log> {
+    let mut logs = Vec::new();
+    for line in lines {
+        match format.parse(&line) {
+            Ok(mut log) => {
+                stats.record_parsed();
+                log.node = node.to_string();
+                logs.push(log);
+            }
+            Err(e) => stats.record_skipped(&line, e),
+        }
+    }
+    logs
+}
+
+/// 从环境变量读取日志来源，格式为 `节点名:格式:路径`，多个来源用逗号分隔，例如
+/// `LOG_SOURCES=edge-1:nginx_json:/var/log/nginx/access.log,edge-2:caddy_json:/var/log/caddy/access.log`。
+/// 没有配置 LOG_SOURCES 时退回到 PARSE_LOG_FILE。
+/// 配置了 SYSLOG_LISTEN 时同时接收 syslog 发来的日志，格式由 SYSLOG_FORMAT 指定，默认为 nginx_json
+pub fn from_env() -> Result<Vec<Box<dyn LogSource>>> {
+    let mut sources = file_sources_from_env()?;
+    if let Ok(addr) = std::env::var("SYSLOG_LISTEN") {
+        let format = match std::env::var("SYSLOG_FORMAT") {
+            Ok(format) => format.parse::<LogFormat>().map_err(|e| anyhow!(e))?,
+            Err(_) => LogFormat::NginxJson,
+        };
+        sources.push(Box::new(SyslogSource::listen(&addr, format)?));
+    }
+    if sources.is_empty() {
+        return Err(anyhow!(
+            "LOG_SOURCES, PARSE_LOG_FILE or SYSLOG_LISTEN must be set"
+        ));
+    }
+    Ok(sources)
+}
+
+fn file_sources_from_env() -> Result<Vec<Box<dyn LogSource>>> {
+    let Ok(config) = std::env::var("LOG_SOURCES") else {
+        let Ok(path) = std::env::var("PARSE_LOG_FILE") else {
+            return Ok(Vec::new());
+        };
+        return Ok(vec![Box::new(FileSource::new(
+            DEFAULT_NODE,
+            LogFormat::NginxJson,
+            path,
+        ))]);
+    };
+    let mut sources: Vec<Box<dyn LogSource>> = Vec::new();
+    for item in config
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+    {
+        let mut parts = item.splitn(3, ':');
+        let (Some(node), Some(format), Some(path)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(anyhow!("log source invalid: {}", item));
+        };
+        let format = format.parse::<LogFormat>().map_err(|e| anyhow!(e))?;
+        sources.push(Box::new(FileSource::new(node, format, path)));
+    }
+    if sources.is_empty() {
+        return Err(anyhow!("LOG_SOURCES is empty"));
+    }
+    Ok(sources)
+}
@@ -0,0 +1,89 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::Serialize;
+
+/// 最多保留多少条坏行样本
+const MAX_SAMPLES: usize = 20;
+/// 样本只保留每行的前 512 个字符
+const MAX_SAMPLE_LEN: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    Empty,
+    /// 不是合法的 json，比如 nginx 把机器人请求里的字节转义成了 '\x'
+    InvalidJson,
+    /// json 合法，但缺少字段或者字段的值不对
+    InvalidField,
+    /// 行不完整，通常是 nginx 还没写完
+    Truncated,
+}
+
+#[derive(Debug, Clone)]
+pub struct LineError {
+    pub reason: SkipReason,
+    pub message: String,
+}
+
+impl LineError {
+    pub fn new(reason: SkipReason, message: impl Into<String>) -> Self {
+        Self {
+            reason,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for LineError {
+    fn from(e: serde_json::Error) -> Self {
+        let reason = match e.classify() {
+            serde_json::error::Category::Eof => SkipReason::Truncated,
+            serde_json::error::Category::Data => SkipReason::InvalidField,
+            _ => SkipReason::InvalidJson,
+        };
+        Self::new(reason, e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BadLine {
+    pub reason: SkipReason,
+    pub error: String,
+    pub line: String,
+}
+
+/// 日志解析统计，按原因记录跳过的行数，并保留最近的坏行样本
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ParseStats {
+    pub lines: u64,
+    pub parsed: u64,
+    pub skipped: BTreeMap<SkipReason, u64>,
+    pub samples: VecDeque<BadLine>,
+}
+
+impl ParseStats {
+    pub fn record_parsed(&mut self) {
+        self.lines += 1;
+        self.parsed += 1;
+    }
+
+    pub fn record_skipped(&mut self, line: &str, error: LineError) {
+        self.lines += 1;
+        *self.skipped.entry(error.reason).or_insert(0) += 1;
+        if error.reason == SkipReason::Empty {
+            return;
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(BadLine {
+            reason: error.reason,
+            error: error.message,
+            line: line.chars().take(MAX_SAMPLE_LEN).collect(),
+        });
+    }
+
+    pub fn skipped_total(&self) -> u64 {
+        self.skipped.values().sum()
+    }
+}
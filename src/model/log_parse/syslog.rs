@@ -0,0 +1,446 @@
+use std::{
+    io::{ErrorKind, Read},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use super::{
+    format::LogFormat,
+    log::Log,
+    source::{self, LogSource},
+    stats::ParseStats,
+};
+
+/// syslog 消息中没有主机名时使用的节点名
+pub const SYSLOG_NODE: &str = "syslog";
+
+/// 读取线程和解析之间最多积压的消息数，积压满了 UDP 的消息会在内核中丢弃
+const BACKLOG: usize = 65536;
+/// 一条消息的最大字节数，更长的帧直接丢弃
+const MAX_FRAME: usize = 64 * 1024;
+/// 同时保持的 TCP 连接数，超过时拒绝新连接
+const MAX_CONNECTIONS: usize = 256;
+/// TCP 连接多久没有数据就断开，断开后的连接不再占用 MAX_CONNECTIONS 的名额
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// UDP 接收连续出错时最长等待的时间
+const MAX_BACKOFF_MS: u64 = 1000;
+
+/// 一条 syslog 消息，payload 是 nginx 写入的日志行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub hostname: Option<String>,
+    pub payload: String,
+}
+
+/// 在同一个地址上监听 UDP 和 TCP 的 syslog，收到的日志和文件中的日志走同一个解析和用量流程。
+/// nginx 中配置 `access_log syslog:server=<addr> json_analytics;` 即可
+pub struct SyslogSource {
+    format: LogFormat,
+    receiver: Receiver<Message>,
+}
+
+impl SyslogSource {
+    pub fn listen(addr: &str, format: LogFormat) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(BACKLOG);
+        let udp = UdpSocket::bind(addr)?;
+        let tcp = TcpListener::bind(addr)?;
+        tracing::info!("syslog listening on {}", addr);
+        let udp_sender = sender.clone();
+        std::thread::spawn(move || serve_udp(udp, udp_sender));
+        std::thread::spawn(move || serve_tcp(tcp, sender));
+        Ok(Self { format, receiver })
+    }
+}
+
+impl LogSource for SyslogSource {
+    fn node(&self) -> &str {
+        SYSLOG_NODE
+    }
+
+    fn poll(&mut self, stats: &mut ParseStats) -> Result<Vec<Log>> {
+        let mut logs = Vec::new();
+        for message in self.receiver.try_iter() {
+            // 每个边缘节点用自己的主机名作为节点名，重启后按节点去重
+            let node = message.hostname.as_deref().unwrap_or(SYSLOG_NODE);
+            logs.extend(source::parse_lines(
+                node,
+                self.format,
+                [message.payload],
+                stats,
+            ));
+        }
+        Ok(logs)
+    }
+}
+
+fn serve_udp(socket: UdpSocket, sender: SyncSender<Message>) {
+    let mut buf = vec![0u8; 65536];
+    let mut backoff_ms = 0;
+    loop {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => {
+                backoff_ms = 0;
+                len
+            }
+            Err(e) => {
+                // 连续出错时逐渐放慢，避免空转
+                tracing::error!("receive syslog failed: {}", e);
+                backoff_ms = (backoff_ms * 2).clamp(10, MAX_BACKOFF_MS);
+                std::thread::sleep(Duration::from_millis(backoff_ms));
+                continue;
+            }
+        };
+        let Some(message) = parse_message(&String::from_utf8_lossy(&buf[..len])) else {
+            continue;
+        };
+        if sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+
+fn serve_tcp(listener: TcpListener, sender: SyncSender<Message>) {
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    tracing::warn!(
+                        "reject syslog connection, {} connections open",
+                        MAX_CONNECTIONS
+                    );
+                    continue;
+                }
+                let (sender, connections) = (sender.clone(), connections.clone());
+                std::thread::spawn(move || {
+                    read_tcp(stream, sender);
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => tracing::error!("accept syslog connection failed: {}", e),
+        }
+    }
+}
+
+fn read_tcp(mut stream: TcpStream, sender: SyncSender<Message>) {
+    if let Err(e) = stream.set_read_timeout(Some(IDLE_TIMEOUT)) {
+        tracing::error!("set syslog read timeout failed: {}", e);
+        return;
+    }
+    let mut framer = Framer::default();
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = match stream.read(&mut buf) {
+            Ok(0) => return,
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                tracing::info!("close idle syslog connection {:?}", stream.peer_addr());
+                return;
+            }
+            Err(e) => {
+                tracing::error!("read syslog connection failed: {}", e);
+                return;
+            }
+        };
+        for frame in framer.push(&buf[..len]) {
+            let Some(message) = parse_message(&frame) else {
+                continue;
+            };
+            if sender.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// TCP 上的 syslog 帧，支持 RFC 6587 的按长度分帧 `<长度> <消息>` 和按换行分帧。
+/// 超过 MAX_FRAME 的帧丢弃，缓冲区不会无限增长
+#[derive(Debug, Default)]
+struct Framer {
+    buf: Vec<u8>,
+    /// 正在丢弃的按长度分帧的帧还剩的字节数
+    skip: usize,
+    /// 正在丢弃一个过长的行，直到下一个换行
+    skip_line: bool,
+}
+
+enum Frame {
+    Complete(Vec<u8>),
+    Dropped,
+    Incomplete,
+}
+
+impl Framer {
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.buf.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            if self.skip > 0 {
+                let n = self.skip.min(self.buf.len());
+                self.buf.drain(..n);
+                self.skip -= n;
+                if self.skip > 0 {
+                    break;
+                }
+            }
+            if self.skip_line {
+                match self.buf.iter().position(|b| *b == b'\n') {
+                    Some(end) => {
+                        self.buf.drain(..=end);
+                        self.skip_line = false;
+                    }
+                    None => {
+                        self.buf.clear();
+                        break;
+                    }
+                }
+            }
+            let start = self
+                .buf
+                .iter()
+                .position(|b| !b.is_ascii_whitespace())
+                .unwrap_or(self.buf.len());
+            self.buf.drain(..start);
+            let Some(&first) = self.buf.first() else {
+                break;
+            };
+            let frame = if first.is_ascii_digit() {
+                self.octet_counted()
+            } else {
+                self.line()
+            };
+            match frame {
+                Frame::Complete(frame) => frames.push(String::from_utf8_lossy(&frame).into_owned()),
+                Frame::Dropped => tracing::warn!("drop syslog frame over {} bytes", MAX_FRAME),
+                Frame::Incomplete => break,
+            }
+        }
+        frames
+    }
+
+    fn octet_counted(&mut self) -> Frame {
+        let digits = self.buf.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == self.buf.len() && digits <= 20 {
+            return Frame::Incomplete;
+        }
+        let len = std::str::from_utf8(&self.buf[..digits])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok());
+        let (Some(len), Some(b' ')) = (len, self.buf.get(digits)) else {
+            // 长度不合法，按换行分帧处理
+            return self.line();
+        };
+        let start = digits + 1;
+        if len > MAX_FRAME {
+            let available = self.buf.len() - start;
+            self.skip = len.saturating_sub(available);
+            self.buf.drain(..start + len.min(available));
+            return Frame::Dropped;
+        }
+        let end = start + len;
+        if self.buf.len() < end {
+            return Frame::Incomplete;
+        }
+        let frame = self.buf[start..end].to_vec();
+        self.buf.drain(..end);
+        Frame::Complete(frame)
+    }
+
+    fn line(&mut self) -> Frame {
+        let Some(end) = self.buf.iter().position(|b| *b == b'\n') else {
+            if self.buf.len() > MAX_FRAME {
+                self.buf.clear();
+                self.skip_line = true;
+                return Frame::Dropped;
+            }
+            return Frame::Incomplete;
+        };
+        let frame: Vec<u8> = self.buf.drain(..=end).take(end).collect();
+        if frame.len() > MAX_FRAME {
+            return Frame::Dropped;
+        }
+        Frame::Complete(frame)
+    }
+}
+
+/// 解析 RFC 3164 和 RFC 5424 格式的消息头，取出主机名和消息内容
+pub fn parse_message(raw: &str) -> Option<Message> {
+    let raw = raw.trim_end_matches(['\r', '\n', '\0']);
+    let rest = raw.strip_prefix('<')?;
+    let (pri, rest) = rest.split_once('>')?;
+    if pri.is_empty() || pri.len() > 3 || !pri.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let message = match rest.strip_prefix("1 ") {
+        Some(rest) => parse_rfc5424(rest),
+        None => parse_rfc3164(rest),
+    }?;
+    (!message.payload.is_empty()).then_some(message)
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG`
+fn parse_rfc5424(rest: &str) -> Option<Message> {
+    let mut fields = rest.splitn(6, ' ');
+    let _timestamp = fields.next()?;
+    let hostname = fields.next()?;
+    let _app = fields.next()?;
+    let _procid = fields.next()?;
+    let _msgid = fields.next()?;
+    let rest = fields.next().unwrap_or_default();
+    let msg = skip_structured_data(rest)?;
+    Some(Message {
+        hostname: nil_value(hostname),
+        payload: msg.trim_start_matches('\u{feff}').to_string(),
+    })
+}
+
+fn skip_structured_data(rest: &str) -> Option<&str> {
+    if let Some(msg) = rest.strip_prefix('-') {
+        return Some(msg.strip_prefix(' ').unwrap_or(msg));
+    }
+    // 一个或多个 `[id key="value"]`，值中的 `\]` 和 `\"` 是转义
+    let mut in_element = false;
+    let mut in_value = false;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_value => escaped = true,
+            '"' if in_element => in_value = !in_value,
+            '[' if !in_element => in_element = true,
+            ']' if in_element && !in_value => in_element = false,
+            ' ' if !in_element => return Some(&rest[i + 1..]),
+            _ if !in_element => return None,
+            _ => {}
+        }
+    }
+    (!in_element).then_some("")
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG: MSG`，nginx 配置了 nohostname 时没有主机名
+fn parse_rfc3164(rest: &str) -> Option<Message> {
+    let rest = rest
+        .get(16..)
+        .filter(|_| rest.as_bytes().get(15) == Some(&b' '))?;
+    let (first, after) = rest.split_once(' ')?;
+    let (hostname, rest) = if first.ends_with(':') || first.ends_with(']') {
+        (None, rest)
+    } else {
+        (nil_value(first), after)
+    };
+    let payload = match rest.split_once(": ") {
+        Some((tag, msg)) if !tag.contains(' ') && !tag.starts_with('{') => msg,
+        _ => rest,
+    };
+    Some(Message {
+        hostname,
+        payload: payload.to_string(),
+    })
+}
+
+fn nil_value(value: &str) -> Option<String> {
+    (value != "-" && !value.is_empty()).then(|| value.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let message = parse_message(r#"<190>Dec  1 02:37:47 edge-1 nginx: {"msec": "1"}"#).unwrap();
+        assert_eq!(message.hostname.as_deref(), Some("edge-1"));
+        assert_eq!(message.payload, r#"{"msec": "1"}"#);
+
+        let message = parse_message(r#"<190>Dec  1 02:37:47 nginx: {"msec": "1"}"#).unwrap();
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.payload, r#"{"msec": "1"}"#);
+
+        let message = parse_message(
+            r#"<165>1 2022-12-01T02:37:47.429Z edge-2 nginx - - [meta a="x\]y"][b c="d"] {"msec": "1"}"#,
+        )
+        .unwrap();
+        assert_eq!(message.hostname.as_deref(), Some("edge-2"));
+        assert_eq!(message.payload, r#"{"msec": "1"}"#);
+
+        let message =
+            parse_message(r#"<165>1 2022-12-01T02:37:47Z - nginx - - - {"msec": "1"}"#).unwrap();
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.payload, r#"{"msec": "1"}"#);
+
+        assert_eq!(parse_message(r#"{"msec": "1"}"#), None);
+        assert_eq!(parse_message("<190>Dec  1 02:37:47 edge-1 nginx: "), None);
+    }
+
+    #[test]
+    fn test_framer() {
+        let mut framer = Framer::default();
+        assert!(framer.push(b"8 <1>ab").is_empty());
+        assert_eq!(framer.push(b"cde<1>f\n<1>g"), vec!["<1>abcde", "<1>f"]);
+        assert_eq!(framer.push(b"\n"), vec!["<1>g"]);
+
+        // 过长的帧丢弃，后面的帧不受影响
+        let big = vec![b'x'; MAX_FRAME + 1];
+        let mut framer = Framer::default();
+        let head = format!("{} ", big.len());
+        assert!(framer.push(head.as_bytes()).is_empty());
+        assert!(framer.push(&big[..1000]).is_empty());
+        assert_eq!(
+            framer.push(&[&big[1000..], &b"4 <1>a"[..]].concat()),
+            vec!["<1>a"]
+        );
+        assert!(framer.push(&big).is_empty());
+        assert_eq!(framer.buf.len(), 0);
+        assert_eq!(framer.push(b"yy\n<1>b\n"), vec!["<1>b"]);
+    }
+
+    #[test]
+    fn test_syslog_source() {
+        use std::{io::Write, net::TcpStream};
+
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let mut source = SyslogSource::listen(&addr, LogFormat::NginxJson).unwrap();
+        let line = std::fs::read_to_string("src/model/test_data/access.log")
+            .unwrap()
+            .lines()
+            .next()
+            .unwrap()
+            .to_string();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.send_to(
+            format!("<190>Dec  1 02:37:47 edge-1 nginx: {}", line).as_bytes(),
+            &addr,
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(&addr).unwrap();
+        let frame = format!("<190>Dec  1 02:37:47 edge-2 nginx: {}", line);
+        write!(tcp, "{} {}", frame.len(), frame).unwrap();
+        tcp.flush().unwrap();
+
+        let mut stats = ParseStats::default();
+        let mut logs = Vec::new();
+        for _ in 0..50 {
+            logs.extend(source.poll(&mut stats).unwrap());
+            if logs.len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let mut nodes: Vec<_> = logs.iter().map(|log| log.node.as_str()).collect();
+        nodes.sort();
+        assert_eq!(nodes, vec!["edge-1", "edge-2"]);
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::PathBuf,
+};
+
+use anyhow::Result;
+
+/// 每次 poll 最多读取的字节数，已经很大的文件分多次读完
+const CHUNK: u64 = 1 << 20;
+
+/// 增量读取一个不断追加的日志文件，记录已读位置和 inode，
+/// 能处理 copytruncate（文件被截断）和 rename（文件被改名后重新创建）两种轮转方式
+#[derive(Debug)]
+pub struct Tailer {
+    path: PathBuf,
+    file: Option<File>,
+    inode: u64,
+    offset: u64,
+    partial: Vec<u8>,
+}
+
+impl Tailer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: None,
+            inode: 0,
+            offset: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// 返回上次调用以来新追加的完整行，最后一行如果还没写完会留到下次。
+    /// 每次最多读 CHUNK 字节，没读完的部分留到下次
+    pub fn poll(&mut self) -> Result<Vec<String>> {
+        let mut lines = Vec::new();
+        if self.file.is_none() && !self.open()? {
+            return Ok(lines);
+        }
+        // 旧文件读到末尾后才切换到新文件
+        if self.read_appended(&mut lines)? && self.rotated() {
+            // 旧文件已经读完，剩下的半行不会再有后续了
+            if !self.partial.is_empty() {
+                tracing::warn!("drop incomplete line at end of rotated {:?}", self.path);
+                self.partial.clear();
+            }
+            self.file = None;
+            if self.open()? {
+                self.read_appended(&mut lines)?;
+            }
+        }
+        Ok(lines)
+    }
+
+    fn open(&mut self) -> Result<bool> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        self.inode = file.metadata()?.ino();
+        self.offset = 0;
+        self.partial.clear();
+        self.file = Some(file);
+        Ok(true)
+    }
+
+    /// 读取最多 CHUNK 字节，返回是否已经读到文件末尾
+    fn read_appended(&mut self, lines: &mut Vec<String>) -> Result<bool> {
+        let Some(file) = self.file.as_mut() else {
+            return Ok(true);
+        };
+        if file.metadata()?.len() < self.offset {
+            tracing::info!("{:?} truncated, read from start", self.path);
+            self.offset = 0;
+            self.partial.clear();
+        }
+        file.seek(SeekFrom::Start(self.offset))?;
+        let mut buf = Vec::new();
+        let n = file.take(CHUNK).read_to_end(&mut buf)?;
+        self.offset += n as u64;
+        self.partial.extend_from_slice(&buf);
+        if let Some(end) = self.partial.iter().rposition(|b| *b == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            let complete = std::mem::replace(&mut self.partial, rest);
+            lines.extend(
+                String::from_utf8_lossy(&complete)
+                    .lines()
+                    .map(|l| l.to_string()),
+            );
+        }
+        Ok((n as u64) < CHUNK)
+    }
+
+    fn rotated(&self) -> bool {
+        match std::fs::metadata(&self.path) {
+            Ok(meta) => meta.ino() != self.inode,
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("tail-{}.log", uuid::Uuid::new_v4()))
+    }
+
+    fn append(path: &PathBuf, s: &str) {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        f.write_all(s.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_tail_append_and_partial_line() {
+        let path = temp_path();
+        let mut tailer = Tailer::new(&path);
+        assert!(tailer.poll().unwrap().is_empty());
+        append(&path, "a\nb\nc");
+        assert_eq!(tailer.poll().unwrap(), vec!["a", "b"]);
+        append(&path, "c\n");
+        assert_eq!(tailer.poll().unwrap(), vec!["cc"]);
+        assert!(tailer.poll().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail_reads_in_chunks() {
+        let path = temp_path();
+        let line = format!("{}\n", "a".repeat(1023));
+        append(&path, &line.repeat(1536));
+        let mut tailer = Tailer::new(&path);
+        assert_eq!(tailer.poll().unwrap().len(), 1024);
+        assert_eq!(tailer.poll().unwrap().len(), 512);
+        assert!(tailer.poll().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail_copytruncate() {
+        let path = temp_path();
+        let mut tailer = Tailer::new(&path);
+        append(&path, "a\nb\n");
+        assert_eq!(tailer.poll().unwrap().len(), 2);
+        std::fs::File::create(&path).unwrap();
+        append(&path, "c\n");
+        assert_eq!(tailer.poll().unwrap(), vec!["c"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tail_rename_rotation() {
+        let path = temp_path();
+        let rotated = path.with_extension("log.1");
+        let mut tailer = Tailer::new(&path);
+        append(&path, "a\n");
+        assert_eq!(tailer.poll().unwrap(), vec!["a"]);
+        append(&path, "b\n");
+        std::fs::rename(&path, &rotated).unwrap();
+        append(&path, "c\n");
+        assert_eq!(tailer.poll().unwrap(), vec!["b", "c"]);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -1,8 +1,13 @@
 pub mod account;
+pub mod anomaly;
 pub mod app;
+pub mod billing;
 pub mod chain;
 pub mod code_examples;
 pub mod db;
+pub mod health;
 pub mod log_parse;
+pub mod registry;
 pub mod tools;
+pub mod usage;
 pub mod init;
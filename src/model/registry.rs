@@ -0,0 +1,750 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use super::code_examples::examples::{self, CodeExample};
+
+static REGISTRY: OnceCell<Registry> = OnceCell::new();
+
+/// 没有配置注册表文件时使用的注册表，包含原来的十条链
+const DEFAULT_REGISTRY: &str = include_str!("../../compose/node-services/chains.toml");
+
+const DEFAULT_MAX_LAG: u64 = 10;
+
+/// 链没有配置地址时返回给前端的内容
+pub const NOT_SUPPORTED: &str = "Not supported yet";
+
+/// 链的注册表，字段说明见 compose/node-services/chains.toml
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Registry {
+    pub chains: Vec<ChainConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainConfig {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub examples: Examples,
+    /// 健康检查调用的方法
+    #[serde(default)]
+    pub probe: Probe,
+    /// 最好的节点落后超过这么多块时认为链已经降级
+    #[serde(default = "default_max_lag")]
+    pub max_lag: u64,
+    /// 落后最高块这么多块的块不会再回滚，代理缓存这些块的查询结果，没有配置时只缓存不变的结果
+    pub finality: Option<u64>,
+    #[serde(default)]
+    pub family: Family,
+    /// 原生代币，网络可以覆盖
+    pub currency: Option<Currency>,
+    pub docs: Option<String>,
+    pub logo: Option<String>,
+    /// 按 JSON-RPC 方法选择节点池的规则，按顺序匹配
+    #[serde(default)]
+    pub rules: Vec<MethodRule>,
+    /// 代理拒绝的方法，写法和 rules 的 methods 一样
+    #[serde(default = "default_blocked")]
+    pub blocked: Vec<String>,
+    /// 第一个网络是主网，应用和 /chains 默认使用它的地址
+    pub networks: Vec<NetworkConfig>,
+}
+
+/// 每个网络有自己的路径和地址，测试网的应用不会拿到主网的地址
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub name: String,
+    /// nginx 中 location 的前缀，websocket 是 `<path>-ws`
+    pub path: String,
+    #[serde(default)]
+    pub status: NetworkStatus,
+    /// 弃用或下线后建议迁移到的网络
+    pub successor: Option<String>,
+    pub chain_id: Option<ChainId>,
+    /// 测试网的代币符号和主网不同时配置
+    pub currency: Option<Currency>,
+    pub explorer: Option<String>,
+    /// 提供给应用的公开地址
+    pub http: Option<String>,
+    pub ws: Option<String>,
+    #[serde(default)]
+    pub upstreams: Upstreams,
+    /// 代理在多个节点之间选择的方式
+    #[serde(default)]
+    pub balance: Balance,
+    /// 用来对比块高的外部节点，例如公共的 rpc，没有配置时只和其它节点对比
+    pub reference: Option<String>,
+    /// 可以覆盖地址的环境变量前缀，加载时根据 path 生成
+    #[serde(skip)]
+    env_names: Vec<String>,
+}
+
+/// 网络的生命周期，下线的网络不能再创建应用，弃用和下线网络上的应用会提示迁移
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NetworkStatus {
+    #[default]
+    Active,
+    Deprecated,
+    Retired,
+}
+
+/// 健康检查时调用的开销很小的方法
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// json-rpc 的 `eth_blockNumber`
+    #[default]
+    EthBlockNumber,
+    /// json-rpc 的 `starknet_blockNumber`
+    StarknetBlockNumber,
+    /// json-rpc 的 `rpc.discover`，没有块高
+    RpcDiscover,
+    /// Sui json-rpc 的 `sui_getLatestCheckpointSequenceNumber`
+    SuiCheckpoint,
+    /// NEAR json-rpc 的 `status`
+    NearStatus,
+    /// Aptos REST api 的 `GET /v1`
+    AptosLedger,
+}
+
+/// 链的接口协议
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Family {
+    #[default]
+    EvmJsonRpc,
+    NearJsonRpc,
+    AptosRest,
+    SuiJsonRpc,
+    StarknetJsonRpc,
+}
+
+/// EVM 链的 chain id 是数字，Starknet、NEAR 和 Sui 是字符串
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ChainId {
+    Number(u64),
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Currency {
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// 节点类型，归档节点保留全部历史状态，可以查询很久以前的块和 `trace_*`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tier {
+    #[default]
+    Full,
+    Archive,
+}
+
+impl fmt::Display for Tier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tier::Full => write!(f, "full"),
+            Tier::Archive => write!(f, "archive"),
+        }
+    }
+}
+
+impl FromStr for Tier {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(Tier::Full),
+            "archive" => Ok(Tier::Archive),
+            _ => Err(anyhow!("{} is not a valid tier", s)),
+        }
+    }
+}
+
+/// nginx 后面的节点地址
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Upstreams {
+    #[serde(default)]
+    pub http: Vec<UpstreamConfig>,
+    #[serde(default)]
+    pub ws: Vec<String>,
+    /// 归档节点，只由 api 代理转发给归档类型的应用
+    #[serde(default)]
+    pub archive: Vec<UpstreamConfig>,
+    /// 专门处理某些方法的节点池，例如开启了 trace 的节点，由链的 rules 选择
+    #[serde(default)]
+    pub pools: BTreeMap<String, Vec<UpstreamConfig>>,
+}
+
+impl Upstreams {
+    /// 某种类型的 http 节点
+    pub fn tier(&self, tier: Tier) -> &[UpstreamConfig] {
+        match tier {
+            Tier::Full => &self.http,
+            Tier::Archive => &self.archive,
+        }
+    }
+
+    /// 所有 http 节点，包括归档节点和专门的节点池
+    pub fn all_http(&self) -> impl Iterator<Item = &UpstreamConfig> + Clone {
+        self.http
+            .iter()
+            .chain(&self.archive)
+            .chain(self.pools.values().flatten())
+    }
+}
+
+/// `methods` 是方法名，或者以 `*` 结尾的前缀，例如 `trace_*`，不区分大小写
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MethodRule {
+    pub methods: Vec<String>,
+    pub pool: String,
+}
+
+impl MethodRule {
+    pub fn matches(&self, method: &str) -> bool {
+        matches_any(&self.methods, method)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.pool.trim().is_empty() {
+            return Err(anyhow!("rule pool is empty"));
+        }
+        if self.methods.is_empty() {
+            return Err(anyhow!("rule for {} has no methods", self.pool));
+        }
+        check_patterns(&self.methods)
+    }
+}
+
+fn matches_any(patterns: &[String], method: &str) -> bool {
+    let method = method.to_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => method == pattern,
+        }
+    })
+}
+
+fn check_patterns(patterns: &[String]) -> Result<()> {
+    for pattern in patterns {
+        // 只支持结尾的一个 `*`
+        let name = pattern.strip_suffix('*').unwrap_or(pattern);
+        if name.is_empty() || name.contains('*') {
+            return Err(anyhow!("method pattern {} invalid", pattern));
+        }
+    }
+    Ok(())
+}
+
+/// 一个节点，可以直接写地址，也可以写成 `{ url = "...", weight = 3 }`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "UpstreamEntry")]
+pub struct UpstreamConfig {
+    pub url: String,
+    pub weight: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamEntry {
+    Url(String),
+    Weighted {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u32,
+    },
+}
+
+impl From<UpstreamEntry> for UpstreamConfig {
+    fn from(entry: UpstreamEntry) -> Self {
+        match entry {
+            UpstreamEntry::Url(url) => Self { url, weight: 1 },
+            UpstreamEntry::Weighted { url, weight } => Self { url, weight },
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// 代理选择节点的方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    /// 按权重轮询
+    #[default]
+    RoundRobin,
+    /// 选择最近延迟最低的节点
+    LeastLatency,
+}
+
+/// 内置示例的名字，或者自定义的模板
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Examples {
+    Builtin(String),
+    Templates(CodeExample),
+}
+
+impl Registry {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut registry: Registry = toml::from_str(content)?;
+        registry.validate()?;
+        for chain in &mut registry.chains {
+            let legacy = env_name(&chain.name);
+            for (i, network) in chain.networks.iter_mut().enumerate() {
+                network.env_names.push(env_name(&network.path));
+                // 兼容原来按链名配置的 `<NAME>_HTTP`，只对主网生效
+                if i == 0 && !network.env_names.contains(&legacy) {
+                    network.env_names.push(legacy.clone());
+                }
+            }
+        }
+        Ok(registry)
+    }
+
+    /// 从 CHAINS_FILE 指定的文件读取，默认是 chains.toml，文件不存在时使用内置的注册表
+    pub fn from_env() -> Result<Self> {
+        let path = std::env::var("CHAINS_FILE").unwrap_or_else(|_| "chains.toml".into());
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content).map_err(|e| anyhow!("{} invalid: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::parse(DEFAULT_REGISTRY),
+            Err(e) => Err(anyhow!("read {} failed: {}", path, e)),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.chains.is_empty() {
+            return Err(anyhow!("no chains"));
+        }
+        let mut names = HashSet::new();
+        let mut paths = HashSet::new();
+        for chain in &self.chains {
+            chain
+                .validate()
+                .map_err(|e| anyhow!("chain {}: {}", chain.name, e))?;
+            if !names.insert(chain.name.to_lowercase()) {
+                return Err(anyhow!("duplicate chain {}", chain.name));
+            }
+            for network in &chain.networks {
+                if !paths.insert(network.path.as_str()) {
+                    return Err(anyhow!("duplicate path {}", network.path));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 按名字查找，不区分大小写
+    pub fn chain(&self, name: &str) -> Option<&ChainConfig> {
+        self.chains
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// 按请求路径的前缀查找链和网络
+    pub fn by_path(&self, path: &str) -> Option<(&ChainConfig, &NetworkConfig)> {
+        self.chains
+            .iter()
+            .find_map(|c| c.networks.iter().find(|n| n.path == path).map(|n| (c, n)))
+    }
+}
+
+impl ChainConfig {
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("name is empty"));
+        }
+        if self.networks.is_empty() {
+            return Err(anyhow!("no networks"));
+        }
+        let mut networks = HashSet::new();
+        for network in &self.networks {
+            if !networks.insert(normalize_network(&network.name)) {
+                return Err(anyhow!("duplicate network {}", network.name));
+            }
+            network
+                .validate()
+                .map_err(|e| anyhow!("network {}: {}", network.name, e))?;
+        }
+        for network in &self.networks {
+            let Some(successor) = &network.successor else {
+                continue;
+            };
+            match self.network(successor) {
+                Some(s) if s.name == network.name => {
+                    return Err(anyhow!("network {} succeeds itself", network.name))
+                }
+                Some(s) if s.status == NetworkStatus::Retired => {
+                    return Err(anyhow!("successor {} is retired", s.name))
+                }
+                Some(_) => {}
+                None => return Err(anyhow!("unknown successor {}", successor)),
+            }
+        }
+        if let Examples::Builtin(name) = &self.examples {
+            if !examples::BUILTIN.contains(&name.as_str()) {
+                return Err(anyhow!("unknown examples {}", name));
+            }
+        }
+        check_patterns(&self.blocked)?;
+        for rule in &self.rules {
+            rule.validate()?;
+            if !self
+                .networks
+                .iter()
+                .any(|n| n.upstreams.pools.contains_key(&rule.pool))
+            {
+                return Err(anyhow!("rule uses unknown pool {}", rule.pool));
+            }
+        }
+        if let Some(currency) = &self.currency {
+            currency.validate()?;
+        }
+        for url in self.docs.iter().chain(&self.logo) {
+            check_url(url, &["http://", "https://"])?;
+        }
+        if self.family == Family::EvmJsonRpc {
+            if let Some(network) = self
+                .networks
+                .iter()
+                .find(|n| matches!(n.chain_id, Some(ChainId::Name(_))))
+            {
+                return Err(anyhow!(
+                    "network {}: evm chain id must be a number",
+                    network.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// 主网，也就是第一个网络
+    pub fn mainnet(&self) -> &NetworkConfig {
+        &self.networks[0]
+    }
+
+    /// 按名字查找网络，`testnet-goerli` 也能找到 `Testnet - Goerli`
+    pub fn network(&self, name: &str) -> Option<&NetworkConfig> {
+        let name = normalize_network(name);
+        self.networks
+            .iter()
+            .find(|n| normalize_network(&n.name) == name)
+    }
+
+    /// 第一个匹配方法的规则选择的节点池
+    pub fn rule_pool(&self, method: &str) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(method))
+            .map(|rule| rule.pool.as_str())
+    }
+
+    /// 代理是否拒绝这个方法
+    pub fn is_blocked(&self, method: &str) -> bool {
+        matches_any(&self.blocked, method)
+    }
+
+    /// 还能创建应用的网络
+    pub fn network_names(&self) -> Vec<String> {
+        self.networks
+            .iter()
+            .filter(|n| n.status != NetworkStatus::Retired)
+            .map(|n| n.name.clone())
+            .collect()
+    }
+
+    /// 有任何一个网络配置了地址就是可用的
+    pub fn is_available(&self) -> bool {
+        self.networks.iter().any(NetworkConfig::is_available)
+    }
+
+    pub fn code_example(&self, link: &str) -> CodeExample {
+        match &self.examples {
+            Examples::Builtin(name) => {
+                examples::get_builtin_example(name, link).unwrap_or_default()
+            }
+            Examples::Templates(templates) => examples::from_templates(templates, link),
+        }
+    }
+}
+
+impl NetworkConfig {
+    fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(anyhow!("name is empty"));
+        }
+        let valid_path = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if self.path.is_empty() || !self.path.chars().all(valid_path) {
+            return Err(anyhow!("path must be lowercase letters, digits or -"));
+        }
+        if self.path.ends_with("-ws") {
+            return Err(anyhow!("path must not end with -ws"));
+        }
+        let http = self.upstreams.all_http();
+        if http.clone().any(|u| u.weight == 0) {
+            return Err(anyhow!("upstream weight must be greater than 0"));
+        }
+        if let Some(currency) = &self.currency {
+            currency.validate()?;
+        }
+        let urls = self
+            .http
+            .iter()
+            .chain(http.map(|u| &u.url))
+            .chain(&self.reference)
+            .chain(&self.explorer);
+        for url in urls {
+            check_url(url, &["http://", "https://"])?;
+        }
+        let urls = self.ws.iter().chain(&self.upstreams.ws);
+        for url in urls {
+            check_url(url, &["http://", "https://", "ws://", "wss://"])?;
+        }
+        Ok(())
+    }
+
+    /// 环境变量 `<PATH>_HTTP` 可以覆盖注册表中的地址，例如 `ETHEREUM_GOERLI_HTTP`
+    pub fn http_address(&self) -> String {
+        self.address("HTTP", &self.http)
+    }
+
+    /// 环境变量 `<PATH>_WS` 可以覆盖注册表中的地址
+    pub fn websocket_address(&self) -> String {
+        self.address("WS", &self.ws)
+    }
+
+    fn address(&self, suffix: &str, configured: &Option<String>) -> String {
+        self.env_names
+            .iter()
+            .find_map(|name| std::env::var(format!("{}_{}", name, suffix)).ok())
+            .or_else(|| configured.clone())
+            .unwrap_or_else(|| NOT_SUPPORTED.to_string())
+    }
+
+    /// 提供的节点类型，全节点总是提供，配置了归档节点时提供归档
+    pub fn tiers(&self) -> Vec<Tier> {
+        let mut tiers = vec![Tier::Full];
+        if !self.upstreams.archive.is_empty() {
+            tiers.push(Tier::Archive);
+        }
+        tiers
+    }
+
+    /// 没有任何地址或者已经下线的网络不能创建应用
+    pub fn is_available(&self) -> bool {
+        self.status != NetworkStatus::Retired
+            && (self.http_address() != NOT_SUPPORTED || self.websocket_address() != NOT_SUPPORTED)
+    }
+}
+
+impl Currency {
+    fn validate(&self) -> Result<()> {
+        if self.symbol.trim().is_empty() {
+            return Err(anyhow!("currency symbol is empty"));
+        }
+        // u128 能表示的最大精度
+        if self.decimals > 38 {
+            return Err(anyhow!("currency decimals must not exceed 38"));
+        }
+        Ok(())
+    }
+}
+
+fn default_max_lag() -> u64 {
+    DEFAULT_MAX_LAG
+}
+
+/// 默认拒绝节点的管理接口和账户接口
+fn default_blocked() -> Vec<String> {
+    ["admin_*", "personal_*", "miner_*"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn check_url(url: &str, schemes: &[&str]) -> Result<()> {
+    if schemes
+        .iter()
+        .any(|s| url.starts_with(s) && url.len() > s.len())
+    {
+        Ok(())
+    } else {
+        Err(anyhow!("{} must start with {}", url, schemes.join(" or ")))
+    }
+}
+
+/// 比较网络名时忽略大小写和空白
+pub fn normalize_network(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect()
+}
+
+/// 环境变量中的名字，例如 `ethereum-goerli` 是 `ETHEREUM_GOERLI`
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// 启动时读取并校验注册表，配置错误时直接报错
+pub fn init() -> Result<()> {
+    let registry = Registry::from_env()?;
+    let _ = REGISTRY.set(registry);
+    Ok(())
+}
+
+/// 当前的注册表，子命令没有调用 init 时也会读取
+pub fn get() -> &'static Registry {
+    REGISTRY.get_or_init(|| {
+        Registry::from_env().unwrap_or_else(|e| {
+            tracing::error!("load chain registry failed: {}", e);
+            Registry::parse(DEFAULT_REGISTRY).expect("default chain registry is invalid")
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::chain::{self, ChainEnum, NetworkEnum, Testnet};
+
+    #[test]
+    fn test_default_registry() {
+        let registry = Registry::parse(DEFAULT_REGISTRY).unwrap();
+        for chain in ChainEnum::ALL {
+            assert!(registry.chain(&chain.to_string()).is_some(), "{}", chain);
+        }
+        let ethereum = registry.chain("ethereum").unwrap();
+        let goerli = ethereum.network("testnet-goerli").unwrap();
+        assert_eq!(goerli.name, "Testnet - Goerli");
+        assert_eq!(goerli.env_names, vec!["ETHEREUM_GOERLI"]);
+        assert_eq!(ethereum.mainnet().env_names, vec!["ETHEREUM"]);
+        assert_eq!(goerli.status, NetworkStatus::Retired);
+        assert_eq!(goerli.successor.as_deref(), Some("Testnet - Sepolia"));
+        assert!(!goerli.is_available());
+        assert!(!ethereum.network_names().contains(&goerli.name));
+        // 旧的接口只返回能用枚举表示的网络，格式不变
+        assert_eq!(chain::network_enums(ethereum), vec![NetworkEnum::Mainnet]);
+        assert_eq!(
+            serde_json::to_value(NetworkEnum::Testnet(Testnet::Goerli)).unwrap(),
+            serde_json::json!({"Testnet": "Goerli"})
+        );
+        assert!(ethereum.network("Testnet - Morden").is_none());
+        let (chain, network) = registry.by_path("starknet").unwrap();
+        assert_eq!(chain.name, "StarkWare");
+        assert_eq!(network.env_names, vec!["STARKNET", "STARKWARE"]);
+        assert_eq!(chain.family, Family::StarknetJsonRpc);
+        assert_eq!(network.chain_id, Some(ChainId::Name("SN_MAIN".into())));
+        let (chain, network) = registry.by_path("bsc-testnet").unwrap();
+        assert_eq!(chain.family, Family::EvmJsonRpc);
+        assert_eq!(network.chain_id, Some(ChainId::Number(97)));
+        assert_eq!(network.currency.as_ref().unwrap().symbol, "tBNB");
+    }
+
+    #[test]
+    fn test_registry_validate() {
+        let chain = |path: &str, examples: &str| {
+            format!(
+                "[[chains]]\nname = \"Base\"\nexamples = {}\n\
+                [[chains.networks]]\nname = \"Mainnet\"\npath = \"{}\"\n\
+                http = \"https://base.example.com\"\n",
+                examples, path
+            )
+        };
+        let templates =
+            r#"{ js = "fetch('${link}')", cli = "curl ${link}", python = "", go = "" }"#;
+        let registry = Registry::parse(&chain("base", templates)).unwrap();
+        let example = registry.chains[0].code_example("https://base.example.com/abc");
+        assert_eq!(example.cli, "curl https://base.example.com/abc");
+
+        assert!(Registry::parse(&chain("base-ws", "\"ethereum\"")).is_err());
+        assert!(Registry::parse(&chain("Base", "\"ethereum\"")).is_err());
+        assert!(Registry::parse(&chain("base", "\"solana\"")).is_err());
+        let duplicate = format!(
+            "{}{}",
+            chain("base", "\"ethereum\""),
+            chain("base", "\"bsc\"")
+        );
+        assert!(Registry::parse(&duplicate).is_err());
+        let bad_url = chain("base", "\"ethereum\"").replace("https://", "ftp://");
+        assert!(Registry::parse(&bad_url).is_err());
+        let metadata = |extra: &str| format!("{}{}\n", chain("base", "\"ethereum\""), extra);
+        assert!(Registry::parse(&metadata("chain_id = 8453")).is_ok());
+        assert!(Registry::parse(&metadata("chain_id = \"base\"")).is_err());
+        assert!(Registry::parse(&metadata("explorer = \"basescan.org\"")).is_err());
+        let archive = "upstreams = { archive = [{ url = \"http://10.0.0.1:8545\", weight = 2 }] }";
+        let registry = Registry::parse(&metadata(archive)).unwrap();
+        let network = registry.chains[0].mainnet();
+        assert_eq!(network.tiers(), vec![Tier::Full, Tier::Archive]);
+        assert_eq!(network.upstreams.tier(Tier::Archive)[0].weight, 2);
+        assert!(Registry::parse(&metadata(&archive.replace("2", "0"))).is_err());
+        let pools = "upstreams = { pools = { trace = [\"http://10.0.0.2:8545\"] } }";
+        let rules = |methods: &str, pool: &str| {
+            format!(
+                "{}{}\n[[chains.rules]]\nmethods = {}\npool = \"{}\"\n",
+                chain("base", "\"ethereum\""),
+                pools,
+                methods,
+                pool
+            )
+        };
+        let registry =
+            Registry::parse(&rules(r#"["debug_trace*", "trace_block"]"#, "trace")).unwrap();
+        let base = &registry.chains[0];
+        assert_eq!(base.rule_pool("debug_traceTransaction"), Some("trace"));
+        assert_eq!(base.rule_pool("TRACE_BLOCK"), Some("trace"));
+        assert_eq!(base.rule_pool("trace_blocks"), None);
+        assert_eq!(base.mainnet().upstreams.all_http().count(), 1);
+        assert!(Registry::parse(&rules(r#"["trace_*"]"#, "broadcast")).is_err());
+        assert!(Registry::parse(&rules(r#"["*"]"#, "trace")).is_err());
+        assert!(base.is_blocked("admin_addPeer"));
+        assert!(!base.is_blocked("eth_accounts"));
+        let open = Registry::parse(&chain("base", "\"ethereum\"\nblocked = []")).unwrap();
+        assert!(!open.chains[0].is_blocked("admin_addPeer"));
+        assert!(Registry::parse(&rules(r#"["*_call"]"#, "trace")).is_err());
+        let currency = "currency = { symbol = \"ETH\", decimals = 18 }";
+        assert!(Registry::parse(&metadata(currency)).is_ok());
+        assert!(Registry::parse(&metadata(&currency.replace("ETH", ""))).is_err());
+
+        let testnet = |status: &str, successor: &str| {
+            format!(
+                "{}[[chains.networks]]\nname = \"Testnet\"\npath = \"base-testnet\"\n\
+                status = \"{}\"\nsuccessor = \"{}\"\n",
+                chain("base", "\"ethereum\""),
+                status,
+                successor
+            )
+        };
+        assert!(Registry::parse(&testnet("deprecated", "mainnet")).is_ok());
+        assert!(Registry::parse(&testnet("retired", "Testnet")).is_err());
+        assert!(Registry::parse(&testnet("retired", "Sepolia")).is_err());
+        assert!(Registry::parse(&testnet("sunset", "Mainnet")).is_err());
+    }
+}
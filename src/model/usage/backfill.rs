@@ -0,0 +1,164 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use serde::Serialize;
+
+use super::{rollup::Rollup, store};
+use crate::model::log_parse::{format::LogFormat, stats::ParseStats};
+
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    pub files: usize,
+    pub loaded: u64,
+    pub duplicated: u64,
+    pub already_stored: u64,
+    pub parse: ParseStats,
+}
+
+/// 从轮转后的日志（目录或 glob，支持 gzip）中补录某个节点的用量，
+/// 有 request_id 的格式按它去重，该节点实时写入过或者补录过的时间范围内的日志会被跳过。
+/// 服务正在写入这个节点的用量时拒绝补录
+pub async fn run(pattern: &str, node: &str, format: LogFormat) -> Result<BackfillReport> {
+    let files = list_files(pattern)?;
+    if files.is_empty() {
+        return Err(anyhow!("no log file matches {}", pattern));
+    }
+    if !store::own(node).await? {
+        return Err(anyhow!(
+            "usage of {} is being written by a running server or another backfill",
+            node
+        ));
+    }
+    let stored = store::stored(node).await?;
+    let mut report = BackfillReport::default();
+    let mut seen = HashSet::new();
+    for path in files {
+        tracing::info!("backfill usage from {:?}", path);
+        let mut rollup = Rollup::default();
+        for line in open(&path)?.lines() {
+            let line = line?;
+            let mut log = match format.parse(&line) {
+                Ok(log) => log,
+                Err(e) => {
+                    report.parse.record_skipped(&line, e);
+                    continue;
+                }
+            };
+            report.parse.record_parsed();
+            log.node = node.to_string();
+            if format.has_request_id() && !seen.insert(log.request_id.clone()) {
+                report.duplicated += 1;
+                continue;
+            }
+            if stored.contains(log.epoch_secs()) {
+                report.already_stored += 1;
+                continue;
+            }
+            rollup.add(&log);
+            report.loaded += 1;
+        }
+        store::write_backfill(&rollup).await?;
+        report.files += 1;
+    }
+    Ok(report)
+}
+
+/// 按轮转顺序从旧到新排列，access.log.3.gz 在 access.log.1 之前，access.log 最后
+fn list_files(pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let mut files: Vec<PathBuf> = if path.is_dir() {
+        std::fs::read_dir(path)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.is_file())
+            .collect()
+    } else {
+        glob::glob(pattern)?
+            .filter_map(|p| p.ok())
+            .filter(|p| p.is_file())
+            .collect()
+    };
+    files.sort_by(|a, b| {
+        rotation_index(b)
+            .cmp(&rotation_index(a))
+            .then_with(|| a.cmp(b))
+    });
+    Ok(files)
+}
+
+fn rotation_index(path: &Path) -> u32 {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    name.trim_end_matches(".gz")
+        .rsplit('.')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+fn open(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|e| e == "gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rotation_order() {
+        let dir = std::env::temp_dir().join(format!("backfill-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "access.log",
+            "access.log.2.gz",
+            "access.log.1",
+            "access.log.10.gz",
+        ] {
+            File::create(dir.join(name)).unwrap();
+        }
+        let expected: Vec<PathBuf> = [
+            "access.log.10.gz",
+            "access.log.2.gz",
+            "access.log.1",
+            "access.log",
+        ]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+        let by_dir = list_files(dir.to_str().unwrap());
+        let by_glob = list_files(dir.join("access.log*").to_str().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(by_dir.unwrap(), expected);
+        assert_eq!(by_glob.unwrap(), expected);
+    }
+
+    #[test]
+    fn test_stored_ranges() {
+        let day = 86400.0;
+        let stored = store::Stored {
+            live: Some(store::Checkpoint {
+                first_msec: 10.0 * day,
+                msec: 11.0 * day,
+            }),
+            backfilled: vec![(day, 2.0 * day), (5.0 * day, 6.0 * day)],
+        };
+        // 补录过第 1 天和第 5 天之后，第 3 天仍然可以补录
+        assert!(stored.contains(1.5 * day));
+        assert!(!stored.contains(3.0 * day));
+        assert!(stored.contains(5.5 * day));
+        assert!(stored.contains(10.5 * day));
+        assert!(!stored.contains(12.0 * day));
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::RwLock,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDate, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use super::rollup::{Count, UsageKey};
+use crate::model::log_parse::log::Log;
+
+static INDEX: Lazy<RwLock<UsageIndex>> = Lazy::new(Default::default);
+
+/// 索引保留最近 9 天，比日志缓存多一天，任何时区的最近 7 天都能覆盖到
+pub const RETENTION_DAYS: i64 = 9;
+/// 每个时间段 15 分钟
+pub const SLOT_SECS: i64 = 900;
+pub const SLOTS_PER_DAY: i64 = 86400 / SLOT_SECS;
+
+/// 一个时间段内的请求计数
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Counters {
+    pub requests: i64,
+    /// 2xx 的请求
+    pub ok: i64,
+    /// 4xx 和 5xx 的请求
+    pub errors: i64,
+    pub compute_units: i64,
+    /// 由代理的响应缓存直接返回的请求
+    pub cache_hits: i64,
+}
+
+impl Counters {
+    pub fn add(&mut self, status_class: &str, count: Count) {
+        self.requests += count.requests;
+        self.compute_units += count.compute_units;
+        self.cache_hits += count.cache_hits;
+        match status_class {
+            "2xx" => self.ok += count.requests,
+            "4xx" | "5xx" => self.errors += count.requests,
+            _ => {}
+        }
+    }
+
+    fn merge(&mut self, other: &Counters) {
+        self.requests += other.requests;
+        self.ok += other.ok;
+        self.errors += other.errors;
+        self.compute_units += other.compute_units;
+        self.cache_hits += other.cache_hits;
+    }
+}
+
+/// 一段时间内的用量，按链细分
+#[derive(Debug, Default, Clone)]
+pub struct Usage {
+    pub total: Counters,
+    pub by_chain: HashMap<String, Counters>,
+}
+
+impl Usage {
+    fn add(&mut self, chain: &str, status_class: &str, count: Count) {
+        self.total.add(status_class, count);
+        self.by_chain
+            .entry(chain.to_string())
+            .or_default()
+            .add(status_class, count);
+    }
+
+    fn merge(&mut self, other: &Usage) {
+        self.total.merge(&other.total);
+        for (chain, counters) in &other.by_chain {
+            self.by_chain
+                .entry(chain.clone())
+                .or_default()
+                .merge(counters);
+        }
+    }
+
+    /// 访问了其它链的请求数
+    pub fn mismatch(&self, chain: &str) -> i64 {
+        let matched = self.by_chain.get(chain).map_or(0, |c| c.requests);
+        self.total.requests - matched
+    }
+}
+
+/// 按 api key 和 UTC 的 15 分钟时间段索引的用量。写入时就汇总好，查询某个时区的一天
+/// 只需要合并 96 个时间段，不需要扫描日志；15 分钟能对齐所有时区的偏移
+#[derive(Debug, Default)]
+pub struct UsageIndex {
+    keys: HashMap<String, BTreeMap<i64, Usage>>,
+    latest_slot: Option<i64>,
+}
+
+impl UsageIndex {
+    pub fn add(&mut self, key: &UsageKey, count: Count) {
+        let slot = key.minute.timestamp().div_euclid(SLOT_SECS);
+        self.keys
+            .entry(key.api_key.clone())
+            .or_default()
+            .entry(slot)
+            .or_default()
+            .add(&key.chain, &key.status_class, count);
+        if self.latest_slot.is_none_or(|latest| slot > latest) {
+            // 进入新的一天时顺便淘汰过期的数据
+            let new_day = self
+                .latest_slot
+                .is_none_or(|latest| slot / SLOTS_PER_DAY > latest / SLOTS_PER_DAY);
+            self.latest_slot = Some(slot);
+            if new_day {
+                self.evict(slot);
+            }
+        }
+    }
+
+    /// [start, end) 之间的用量
+    pub fn range(&self, api_key: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> Usage {
+        let mut usage = Usage::default();
+        let Some(slots) = self.keys.get(api_key) else {
+            return usage;
+        };
+        let start = start.timestamp().div_euclid(SLOT_SECS);
+        let end = end.timestamp().div_euclid(SLOT_SECS);
+        for (_, slot) in slots.range(start..end) {
+            usage.merge(slot);
+        }
+        usage
+    }
+
+    /// 某个时区的某一天的用量
+    pub fn day(&self, api_key: &str, day: NaiveDate, tz: Tz) -> Usage {
+        let (start, end) = day_range(day, tz);
+        self.range(api_key, start, end)
+    }
+
+    /// 最新一条用量所在的时间段编号，编号是 unix 秒数除以 SLOT_SECS
+    pub fn latest_slot(&self) -> Option<i64> {
+        self.latest_slot
+    }
+
+    /// [start, end) 之间每个时间段的计数，没有请求的时间段为 0
+    pub fn series(&self, api_key: &str, start: i64, end: i64) -> Vec<Counters> {
+        let mut series = vec![Counters::default(); (end - start).max(0) as usize];
+        if let Some(slots) = self.keys.get(api_key) {
+            for (slot, usage) in slots.range(start..end) {
+                series[(slot - start) as usize] = usage.total;
+            }
+        }
+        series
+    }
+
+    /// 在某个时间段有请求的 api key
+    pub fn active_keys(&self, slot: i64) -> impl Iterator<Item = &str> {
+        self.keys
+            .iter()
+            .filter(move |(_, slots)| slots.contains_key(&slot))
+            .map(|(key, _)| key.as_str())
+    }
+
+    fn evict(&mut self, latest: i64) {
+        let cutoff = latest - RETENTION_DAYS * SLOTS_PER_DAY;
+        self.keys.retain(|_, slots| {
+            *slots = slots.split_off(&cutoff);
+            !slots.is_empty()
+        });
+    }
+}
+
+/// 某个时区的一天对应的 UTC 时间范围，夏令时切换的那天可能是 23 或 25 小时
+pub fn day_range(day: NaiveDate, tz: Tz) -> (DateTime<Utc>, DateTime<Utc>) {
+    let start_of = |day: NaiveDate| {
+        let midnight = day.and_hms_opt(0, 0, 0).unwrap_or_default();
+        // 午夜不存在时（极少数时区在午夜切换夏令时）按 UTC 的午夜减去偏移处理
+        tz.from_local_datetime(&midnight)
+            .earliest()
+            .map(|t| t.with_timezone(&Utc))
+            .unwrap_or_else(|| {
+                let offset = tz.offset_from_utc_datetime(&midnight).fix();
+                Utc.from_utc_datetime(&midnight)
+                    - chrono::Duration::seconds(offset.local_minus_utc() as i64)
+            })
+    };
+    let next = day.succ_opt().unwrap_or(day);
+    (start_of(day), start_of(next))
+}
+
+/// 把一条日志累加到索引中
+pub fn record(log: &Log) {
+    let Some(key) = UsageKey::from_log(log) else {
+        return;
+    };
+    let count = Count::from_log(log, &key);
+    add(&key, count);
+}
+
+/// 累加已经聚合好的用量，启动时用数据库中的分钟数据预热
+pub fn add(key: &UsageKey, count: Count) {
+    match INDEX.write() {
+        Ok(mut index) => index.add(key, count),
+        Err(e) => tracing::error!("index usage failed: {}", e),
+    }
+}
+
+/// 在读锁下查询索引
+pub fn read<T>(f: impl FnOnce(&UsageIndex) -> T) -> Result<T> {
+    let index = INDEX
+        .read()
+        .map_err(|e| anyhow!("read usage index failed: {}", e))?;
+    Ok(f(&index))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn count(requests: i64) -> Count {
+        Count {
+            requests,
+            compute_units: requests,
+            cache_hits: 0,
+        }
+    }
+
+    fn key(chain: &str, status_class: &str, secs: i64) -> UsageKey {
+        UsageKey {
+            api_key: "0123abcd".to_string(),
+            chain: chain.to_string(),
+            status_class: status_class.to_string(),
+            minute: Utc.timestamp_opt(secs, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_usage_index() {
+        let mut index = UsageIndex::default();
+        // 2022-12-01 02:37:00 UTC
+        index.add(&key("Ethereum", "2xx", 1669862220), count(3));
+        index.add(&key("Ethereum", "5xx", 1669862220), count(1));
+        // 2022-11-30 23:30:00 UTC，上海时间已经是 12 月 1 日
+        index.add(&key("Bsc", "2xx", 1669851000), count(2));
+        let day = NaiveDate::from_ymd_opt(2022, 12, 1).unwrap();
+        let usage = index.day("0123abcd", day, Tz::UTC);
+        assert_eq!(usage.total.requests, 4);
+        assert_eq!(usage.total.ok, 3);
+        assert_eq!(usage.total.errors, 1);
+        assert_eq!(usage.mismatch("Ethereum"), 0);
+
+        let usage = index.day("0123abcd", day, Tz::Asia__Shanghai);
+        assert_eq!(usage.total.requests, 6);
+        assert_eq!(usage.mismatch("Ethereum"), 2);
+
+        // 10 天后的数据到达，旧的数据被淘汰
+        index.add(&key("Ethereum", "2xx", 1669862220 + 10 * 86400), count(1));
+        assert_eq!(index.day("0123abcd", day, Tz::UTC).total.requests, 0);
+    }
+
+    #[test]
+    fn test_day_range() {
+        let day = NaiveDate::from_ymd_opt(2022, 12, 1).unwrap();
+        let (start, end) = day_range(day, Tz::Asia__Kolkata);
+        assert_eq!(start.to_rfc3339(), "2022-11-30T18:30:00+00:00");
+        assert_eq!(end - start, chrono::Duration::hours(24));
+        // 纽约 2022-11-06 从夏令时切回来，这一天有 25 小时
+        let day = NaiveDate::from_ymd_opt(2022, 11, 6).unwrap();
+        let (start, end) = day_range(day, Tz::America__New_York);
+        assert_eq!(end - start, chrono::Duration::hours(25));
+        // 圣保罗 2018-11-04 在午夜进入夏令时，这一天从当地 1 点开始
+        let day = NaiveDate::from_ymd_opt(2018, 11, 4).unwrap();
+        let (start, _) = day_range(day, Tz::America__Sao_Paulo);
+        assert_eq!(start.to_rfc3339(), "2018-11-04T03:00:00+00:00");
+    }
+}
@@ -0,0 +1,16 @@
+pub mod backfill;
+pub mod index;
+pub mod report;
+pub mod rollup;
+pub mod store;
+
+use crate::model::log_parse::log::Log;
+
+/// 记录一条新解析到的日志：累加到内存索引，并等待写入数据库
+pub fn record(log: &Log) {
+    if store::is_stored(log) {
+        return;
+    }
+    index::record(log);
+    store::record(log);
+}
@@ -0,0 +1,223 @@
+use std::{collections::BTreeMap, fmt::Write, str::FromStr};
+
+use anyhow::Result;
+use chrono::NaiveDate;
+use serde::Serialize;
+
+use super::{index::Counters, rollup::Rollup};
+use crate::model::log_parse::{log::Log, stats::ParseStats};
+
+/// 报表的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(ReportFormat::Table),
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => Err(format!("{} is not a valid report format", s)),
+        }
+    }
+}
+
+/// 只统计符合条件的请求，日期按 UTC 计算，包含 from 和 to 当天
+#[derive(Debug, Default, Clone)]
+pub struct ReportFilter {
+    pub api_key: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+impl ReportFilter {
+    fn contains(&self, api_key: &str, day: NaiveDate) -> bool {
+        self.api_key.as_deref().is_none_or(|key| key == api_key)
+            && self.from.is_none_or(|from| day >= from)
+            && self.to.is_none_or(|to| day <= to)
+    }
+}
+
+/// 某个 api key 某天在某条链上的用量
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportRow {
+    pub day: NaiveDate,
+    pub api_key: String,
+    pub chain: String,
+    #[serde(flatten)]
+    pub counters: Counters,
+}
+
+/// 不依赖数据库，直接从日志文件统计出来的用量
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub rows: Vec<ReportRow>,
+    pub total: Counters,
+    pub parse: ParseStats,
+}
+
+impl Report {
+    /// 解析整个日志文件并按天、api key 和链汇总
+    pub fn from_file(path: &str, filter: &ReportFilter) -> Result<Self> {
+        let (logs, parse) = Log::parse_file_with_stats(path)?;
+        let mut report = Self::from_logs(&logs, filter);
+        report.parse = parse;
+        Ok(report)
+    }
+
+    pub fn from_logs(logs: &[Log], filter: &ReportFilter) -> Self {
+        let mut rollup = Rollup::default();
+        logs.iter().for_each(|log| rollup.add(log));
+        let mut rows: BTreeMap<(NaiveDate, String, String), Counters> = BTreeMap::new();
+        let mut total = Counters::default();
+        for ((api_key, chain, status_class, day), count) in rollup.daily() {
+            if !filter.contains(&api_key, day) {
+                continue;
+            }
+            total.add(&status_class, count);
+            rows.entry((day, api_key, chain))
+                .or_default()
+                .add(&status_class, count);
+        }
+        let rows = rows
+            .into_iter()
+            .map(|((day, api_key, chain), counters)| ReportRow {
+                day,
+                api_key,
+                chain,
+                counters,
+            })
+            .collect();
+        Self {
+            rows,
+            total,
+            parse: ParseStats::default(),
+        }
+    }
+
+    pub fn render(&self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Table => self.to_table(),
+            ReportFormat::Json => serde_json::to_string_pretty(self).unwrap_or_default(),
+            ReportFormat::Csv => self.to_csv(),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        let mut out = String::from("day,api_key,chain,requests,ok,errors,compute_units\n");
+        for row in &self.rows {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                row.day,
+                row.api_key,
+                row.chain,
+                row.counters.requests,
+                row.counters.ok,
+                row.counters.errors,
+                row.counters.compute_units
+            );
+        }
+        out
+    }
+
+    fn to_table(&self) -> String {
+        let key_width = self
+            .rows
+            .iter()
+            .map(|row| row.api_key.len())
+            .max()
+            .unwrap_or(0)
+            .max("api_key".len());
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:<10}  {:<key_width$}  {:<10}  {:>10}  {:>10}  {:>10}  {:>13}",
+            "day", "api_key", "chain", "requests", "ok", "errors", "compute_units"
+        );
+        for row in &self.rows {
+            let _ = writeln!(
+                out,
+                "{:<10}  {:<key_width$}  {:<10}  {:>10}  {:>10}  {:>10}  {:>13}",
+                row.day.to_string(),
+                row.api_key,
+                row.chain,
+                row.counters.requests,
+                row.counters.ok,
+                row.counters.errors,
+                row.counters.compute_units
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{:<10}  {:<key_width$}  {:<10}  {:>10}  {:>10}  {:>10}  {:>13}",
+            "total",
+            "",
+            "",
+            self.total.requests,
+            self.total.ok,
+            self.total.errors,
+            self.total.compute_units
+        );
+        if self.parse.skipped_total() > 0 {
+            let _ = writeln!(
+                out,
+                "skipped {} of {} lines",
+                self.parse.skipped_total(),
+                self.parse.lines
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    fn log(uri: &str, status: u16, secs: i64) -> Log {
+        Log {
+            request_uri: uri.to_string(),
+            status,
+            msec: Utc.timestamp_opt(secs, 0).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_report_from_logs() {
+        // 2022-12-01 和 2022-12-02
+        let logs = vec![
+            log("/ethereum/0123abcd", 200, 1669862267),
+            log("/ethereum/0123abcd", 502, 1669862268),
+            log("/bsc/0123abcd", 200, 1669948667),
+            log("/ethereum/4567ef", 200, 1669948667),
+            log("/favicon.ico", 404, 1669948667),
+        ];
+        let report = Report::from_logs(&logs, &ReportFilter::default());
+        assert_eq!(report.rows.len(), 3);
+        assert_eq!(report.total.requests, 4);
+        assert_eq!(report.total.errors, 1);
+
+        let filter = ReportFilter {
+            api_key: Some("0123abcd".to_string()),
+            from: NaiveDate::from_ymd_opt(2022, 12, 2),
+            to: None,
+        };
+        let report = Report::from_logs(&logs, &filter);
+        assert_eq!(report.rows.len(), 1);
+        assert_eq!(report.rows[0].chain, "Bsc");
+        assert_eq!(
+            report.render(ReportFormat::Csv),
+            "day,api_key,chain,requests,ok,errors,compute_units\n2022-12-02,0123abcd,Bsc,1,1,0,1\n"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use std::{collections::HashMap, ops::AddAssign};
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+use crate::model::{
+    billing,
+    log_parse::{log::Log, route::Route},
+};
+
+/// 按分钟聚合的维度
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UsageKey {
+    pub api_key: String,
+    pub chain: String,
+    pub status_class: String,
+    pub minute: DateTime<Utc>,
+}
+
+impl UsageKey {
+    pub fn from_log(log: &Log) -> Option<Self> {
+        let route = Route::parse(&log.request_uri)?;
+        let secs = log.msec.timestamp();
+        Some(Self {
+            api_key: route.api_key,
+            chain: route.chain,
+            status_class: status_class(log.status),
+            minute: Utc.timestamp_opt(secs - secs % 60, 0).single()?,
+        })
+    }
+
+    pub fn day(&self) -> NaiveDate {
+        self.minute.date_naive()
+    }
+}
+
+/// 请求数和计费用的计算单位，只有成功的请求计费，代理从归档节点池返回的请求按倍数计费。
+/// cache_hits 是由代理的响应缓存直接返回的请求数，同样计费
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Count {
+    pub requests: i64,
+    pub compute_units: i64,
+    pub cache_hits: i64,
+}
+
+impl Count {
+    pub fn from_log(log: &Log, key: &UsageKey) -> Self {
+        let compute_units = if key.status_class == "2xx" {
+            billing::compute_units(&key.chain, log.tier.unwrap_or_default(), &log.rpc_methods)
+        } else {
+            0
+        };
+        // 批量请求中的每个调用算一个请求
+        let requests = log.rpc_methods.len().max(1) as i64;
+        let cache_hits = if log.upstream_cache_status.as_deref() == Some("HIT") {
+            requests
+        } else {
+            0
+        };
+        Self {
+            requests,
+            compute_units,
+            cache_hits,
+        }
+    }
+}
+
+impl AddAssign for Count {
+    fn add_assign(&mut self, other: Self) {
+        self.requests += other.requests;
+        self.compute_units += other.compute_units;
+        self.cache_hits += other.cache_hits;
+    }
+}
+
+/// 还没写入数据库的聚合结果
+#[derive(Debug, Default)]
+pub struct Rollup {
+    pub minutes: HashMap<UsageKey, Count>,
+    /// 每个日志来源已聚合日志的时间范围，(最早, 最新)，单位是秒
+    pub ranges: HashMap<String, (f64, f64)>,
+}
+
+impl Rollup {
+    pub fn add(&mut self, log: &Log) {
+        self.extend_range(&log.node, log.epoch_secs(), log.epoch_secs());
+        let Some(key) = UsageKey::from_log(log) else {
+            return;
+        };
+        let count = Count::from_log(log, &key);
+        *self.minutes.entry(key).or_default() += count;
+    }
+
+    pub fn merge(&mut self, other: Rollup) {
+        for (key, count) in other.minutes {
+            *self.minutes.entry(key).or_default() += count;
+        }
+        for (source, (earliest, latest)) in other.ranges {
+            self.extend_range(&source, earliest, latest);
+        }
+    }
+
+    fn extend_range(&mut self, source: &str, earliest: f64, latest: f64) {
+        let range = self
+            .ranges
+            .entry(source.to_string())
+            .or_insert((earliest, latest));
+        range.0 = range.0.min(earliest);
+        range.1 = range.1.max(latest);
+    }
+
+    /// 把分钟数据汇总成天
+    pub fn daily(&self) -> HashMap<(String, String, String, NaiveDate), Count> {
+        let mut days: HashMap<_, Count> = HashMap::new();
+        for (key, count) in &self.minutes {
+            *days
+                .entry((
+                    key.api_key.clone(),
+                    key.chain.clone(),
+                    key.status_class.clone(),
+                    key.day(),
+                ))
+                .or_default() += *count;
+        }
+        days
+    }
+
+    /// 每个 api key 每小时在每条链上用掉的计算单位，用于扣费
+    pub fn hourly_units(&self) -> HashMap<(String, String, DateTime<Utc>), i64> {
+        let mut hours = HashMap::new();
+        for (key, count) in &self.minutes {
+            if count.compute_units == 0 {
+                continue;
+            }
+            let secs = key.minute.timestamp();
+            let Some(hour) = Utc.timestamp_opt(secs - secs % 3600, 0).single() else {
+                continue;
+            };
+            *hours
+                .entry((key.api_key.clone(), key.chain.clone(), hour))
+                .or_insert(0) += count.compute_units;
+        }
+        hours
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.minutes.is_empty() && self.ranges.is_empty()
+    }
+}
+
+/// 200 -> "2xx"，不在合法范围内的状态码归为 "other"
+pub fn status_class(status: u16) -> String {
+    if (100..600).contains(&status) {
+        format!("{}xx", status / 100)
+    } else {
+        "other".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rollup_from_file() {
+        let logs = Log::parse_file("src/model/test_data/access.log").unwrap();
+        let mut rollup = Rollup::default();
+        logs.iter().for_each(|log| rollup.add(log));
+        // 测试数据里只有访问 / 和 /favicon.ico 的请求，没有 api key
+        assert!(rollup.minutes.is_empty());
+        assert_eq!(
+            rollup.ranges.get(""),
+            Some(&(1669862267.429, 1670225080.772))
+        );
+    }
+
+    #[test]
+    fn test_batch_counts_every_call() {
+        let log = |status: u16, methods: &[&str]| Log {
+            request_uri: "/ethereum/0123abcd".to_string(),
+            status,
+            rpc_methods: methods.iter().map(|m| m.to_string()).collect(),
+            ..Default::default()
+        };
+        let mut rollup = Rollup::default();
+        rollup.add(&log(
+            200,
+            &["eth_chainId", "eth_blockNumber", "eth_getBalance"],
+        ));
+        rollup.add(&log(400, &["admin_peers"]));
+        rollup.add(&log(200, &[]));
+        let requests: i64 = rollup.minutes.values().map(|c| c.requests).sum();
+        assert_eq!(requests, 5);
+
+        // 缓存返回的调用计入请求数和命中数
+        rollup.add(&Log {
+            upstream_cache_status: Some("HIT".to_string()),
+            ..log(200, &["eth_chainId", "net_version"])
+        });
+        let count = rollup.minutes.values().fold(Count::default(), |mut a, &b| {
+            a += b;
+            a
+        });
+        assert_eq!((count.requests, count.cache_hits), (7, 2));
+    }
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(200), "2xx");
+        assert_eq!(status_class(404), "4xx");
+        assert_eq!(status_class(0), "other");
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use once_cell::sync::OnceCell;
+use sqlx::{PgConnection, Postgres, Transaction};
+
+use super::{
+    index,
+    rollup::{Count, Rollup, UsageKey},
+};
+use crate::model::{billing, db, log_parse::log::Log};
+
+static PENDING: OnceCell<Mutex<Rollup>> = OnceCell::new();
+static CHECKPOINTS: OnceCell<HashMap<String, Stored>> = OnceCell::new();
+/// 持有日志来源的 advisory lock 的连接和已经持有的来源
+static OWNER: OnceCell<tokio::sync::Mutex<(PgConnection, HashSet<String>)>> = OnceCell::new();
+
+/// advisory lock 的第一个键，和其它用途的锁区分开
+const LOCK_CLASS: i32 = 0x7573_6167;
+
+/// 已经写入数据库的日志时间范围，单位是秒
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint {
+    pub first_msec: f64,
+    pub msec: f64,
+}
+
+/// 某个日志来源已经写入数据库的日志：服务实时写入的连续范围和每次补录写入的范围
+#[derive(Debug, Clone, Default)]
+pub struct Stored {
+    pub live: Option<Checkpoint>,
+    pub backfilled: Vec<(f64, f64)>,
+}
+
+impl Stored {
+    /// 补录时这个时间的日志是否已经写入过
+    pub fn contains(&self, t: f64) -> bool {
+        self.live.is_some_and(|c| c.first_msec <= t && t <= c.msec) || self.in_backfill(t)
+    }
+
+    fn in_backfill(&self, t: f64) -> bool {
+        self.backfilled
+            .iter()
+            .any(|&(first, last)| first <= t && t <= last)
+    }
+}
+
+const FLUSH_INTERVAL_SECS: u64 = 10;
+/// 分钟级数据保留 30 天，天级数据一直保留
+const MINUTE_RETENTION_DAYS: i64 = 30;
+
+/// 读取上次持久化的位置并启动定时写库任务，必须在日志缓存初始化之前调用
+pub async fn init() -> Result<()> {
+    let checkpoints = load_stored().await?;
+    for source in checkpoints.keys() {
+        if !own(source).await? {
+            return Err(anyhow!("usage of {} is being backfilled", source));
+        }
+    }
+    if CHECKPOINTS.set(checkpoints).is_err() {
+        return Ok(());
+    }
+    warm_index().await?;
+    let _ = PENDING.set(Mutex::new(Rollup::default()));
+    tokio::spawn(async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(FLUSH_INTERVAL_SECS));
+        let mut last_cleanup = std::time::Instant::now();
+        loop {
+            ticker.tick().await;
+            if let Err(e) = flush().await {
+                tracing::error!("flush usage failed: {}", e);
+            }
+            if last_cleanup.elapsed() > Duration::from_secs(3600) {
+                last_cleanup = std::time::Instant::now();
+                if let Err(e) = cleanup().await {
+                    tracing::error!("cleanup usage failed: {}", e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 这条日志是否已经写入过数据库。重启后会从头读取日志文件，这些日志不能再算一次，
+/// 补录过的日志也不能再算
+pub fn is_stored(log: &Log) -> bool {
+    CHECKPOINTS.get().is_some_and(|checkpoints| {
+        checkpoints.get(&log.node).is_some_and(|stored| {
+            let t = log.epoch_secs();
+            stored.live.is_some_and(|c| t <= c.msec) || stored.in_backfill(t)
+        })
+    })
+}
+
+/// 占用一个日志来源，服务和补录不能同时写入同一个来源，否则同样的日志会算两次。
+/// 锁在进程退出、连接断开时释放，已经被其它进程占用时返回 false
+pub async fn own(source: &str) -> Result<bool> {
+    let owner = match OWNER.get() {
+        Some(owner) => owner,
+        None => {
+            let conn = db::get_pool()?.acquire().await?.detach();
+            let _ = OWNER.set(tokio::sync::Mutex::new((conn, HashSet::new())));
+            OWNER
+                .get()
+                .ok_or_else(|| anyhow!("usage lock not initialized"))?
+        }
+    };
+    let mut owner = owner.lock().await;
+    let (conn, owned) = &mut *owner;
+    if owned.contains(source) {
+        return Ok(true);
+    }
+    let locked = sqlx::query_scalar!(
+        "SELECT pg_try_advisory_lock($1, hashtext($2))",
+        LOCK_CLASS,
+        source
+    )
+    .fetch_one(conn)
+    .await?
+    .unwrap_or(false);
+    if locked {
+        owned.insert(source.to_string());
+    }
+    Ok(locked)
+}
+
+/// 记录一条新解析到的日志，未初始化持久化存储时什么也不做
+pub fn record(log: &Log) {
+    let Some(pending) = PENDING.get() else {
+        return;
+    };
+    match pending.lock() {
+        Ok(mut pending) => pending.add(log),
+        Err(e) => tracing::error!("record usage failed: {}", e),
+    }
+}
+
+pub async fn flush() -> Result<()> {
+    let Some(pending) = PENDING.get() else {
+        return Ok(());
+    };
+    let rollup = std::mem::take(&mut *pending.lock().map_err(|e| anyhow!("{}", e))?);
+    if rollup.is_empty() {
+        return Ok(());
+    }
+    // 补录正在占用某个来源时先不写，放回去等下次
+    let mut result = Ok(());
+    for source in rollup.ranges.keys() {
+        match own(source).await {
+            Ok(true) => {}
+            Ok(false) => result = Err(anyhow!("usage of {} is being backfilled", source)),
+            Err(e) => result = Err(e),
+        }
+    }
+    if result.is_ok() {
+        result = write(&rollup).await;
+    }
+    if let Err(e) = result {
+        // 写库失败就放回去，下次再试
+        if let Ok(mut pending) = pending.lock() {
+            pending.merge(rollup);
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// 某个日志来源已经写入数据库的日志
+pub async fn stored(source: &str) -> Result<Stored> {
+    Ok(load_stored().await?.remove(source).unwrap_or_default())
+}
+
+async fn load_stored() -> Result<HashMap<String, Stored>> {
+    let pool = db::get_pool()?;
+    let mut stored: HashMap<String, Stored> = HashMap::new();
+    let rows = sqlx::query!("SELECT source, first_msec, msec FROM usage_checkpoint")
+        .fetch_all(&pool)
+        .await?;
+    for r in rows {
+        stored.entry(r.source).or_default().live = Some(Checkpoint {
+            first_msec: r.first_msec,
+            msec: r.msec,
+        });
+    }
+    let rows = sqlx::query!("SELECT source, first_msec, msec FROM usage_backfill")
+        .fetch_all(&pool)
+        .await?;
+    for r in rows {
+        stored
+            .entry(r.source)
+            .or_default()
+            .backfilled
+            .push((r.first_msec, r.msec));
+    }
+    Ok(stored)
+}
+
+/// 把实时的聚合结果累加到数据库中、扣除计算单位，并扩展已写入的时间范围
+pub async fn write(rollup: &Rollup) -> Result<()> {
+    let mut tx = db::get_pool()?.begin().await?;
+    write_usage(&mut tx, rollup).await?;
+    billing::debit(&mut tx, &rollup.hourly_units()).await?;
+    for (source, (earliest, latest)) in &rollup.ranges {
+        sqlx::query!(
+            "INSERT INTO usage_checkpoint (source, first_msec, msec) VALUES ($1, $2, $3)
+            ON CONFLICT (source)
+            DO UPDATE SET
+                first_msec = LEAST(usage_checkpoint.first_msec, EXCLUDED.first_msec),
+                msec = GREATEST(usage_checkpoint.msec, EXCLUDED.msec);",
+            source,
+            earliest,
+            latest,
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// 把补录的聚合结果累加到数据库中，单独记下这次补录的时间范围，不扩展实时写入的范围。
+/// 补录只写 usage_minute/usage_daily，不扣除计算单位
+pub async fn write_backfill(rollup: &Rollup) -> Result<()> {
+    let mut tx = db::get_pool()?.begin().await?;
+    write_usage(&mut tx, rollup).await?;
+    for (source, (earliest, latest)) in &rollup.ranges {
+        sqlx::query!(
+            "INSERT INTO usage_backfill (source, first_msec, msec) VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING;",
+            source,
+            earliest,
+            latest,
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// 累加分钟和天的用量，不涉及扣费
+async fn write_usage(tx: &mut Transaction<'_, Postgres>, rollup: &Rollup) -> Result<()> {
+    for (key, count) in &rollup.minutes {
+        sqlx::query!(
+            "INSERT INTO usage_minute (
+                api_key, chain, status_class, minute, requests, compute_units, cache_hits
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7
+            )
+            ON CONFLICT (api_key, chain, status_class, minute)
+            DO UPDATE SET
+                requests = usage_minute.requests + EXCLUDED.requests,
+                compute_units = usage_minute.compute_units + EXCLUDED.compute_units,
+                cache_hits = usage_minute.cache_hits + EXCLUDED.cache_hits;",
+            key.api_key,
+            key.chain,
+            key.status_class,
+            key.minute,
+            count.requests,
+            count.compute_units,
+            count.cache_hits,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    for ((api_key, chain, status_class, day), count) in rollup.daily() {
+        sqlx::query!(
+            "INSERT INTO usage_daily (
+                api_key, chain, status_class, day, requests, compute_units, cache_hits
+            ) VALUES (
+                $1, $2, $3, $4, $5, $6, $7
+            )
+            ON CONFLICT (api_key, chain, status_class, day)
+            DO UPDATE SET
+                requests = usage_daily.requests + EXCLUDED.requests,
+                compute_units = usage_daily.compute_units + EXCLUDED.compute_units,
+                cache_hits = usage_daily.cache_hits + EXCLUDED.cache_hits;",
+            api_key,
+            chain,
+            status_class,
+            day,
+            count.requests,
+            count.compute_units,
+            count.cache_hits,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// 已经写入数据库的用量不会再从日志中计入索引，启动时从分钟数据加载回来
+async fn warm_index() -> Result<()> {
+    let since = Utc::now() - chrono::Duration::days(index::RETENTION_DAYS);
+    let rows = sqlx::query!(
+        "SELECT api_key, chain, status_class, minute, requests, compute_units,
+            cache_hits
+        FROM usage_minute WHERE minute >= $1;",
+        since
+    )
+    .fetch_all(&db::get_pool()?)
+    .await?;
+    for row in rows {
+        let key = UsageKey {
+            api_key: row.api_key,
+            chain: row.chain,
+            status_class: row.status_class,
+            minute: row.minute,
+        };
+        let count = Count {
+            requests: row.requests,
+            compute_units: row.compute_units,
+            cache_hits: row.cache_hits,
+        };
+        index::add(&key, count);
+    }
+    Ok(())
+}
+
+async fn cleanup() -> Result<()> {
+    let cutoff = Utc::now() - chrono::Duration::days(MINUTE_RETENTION_DAYS);
+    sqlx::query!("DELETE FROM usage_minute WHERE minute < $1;", cutoff)
+        .execute(&db::get_pool()?)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde_json::{json, Value};
+
+/// JSON-RPC 2.0 的错误码
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_ALLOWED: i64 = -32601;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// 检查一个调用是否符合 JSON-RPC 2.0
+pub fn validate(call: &Value) -> Result<(), &'static str> {
+    let Some(call) = call.as_object() else {
+        return Err("call must be an object");
+    };
+    if call.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err("jsonrpc must be \"2.0\"");
+    }
+    match call.get("method").and_then(Value::as_str) {
+        Some(method) if !method.is_empty() => {}
+        _ => return Err("method must be a non-empty string"),
+    }
+    if !matches!(
+        call.get("params"),
+        None | Some(Value::Array(_) | Value::Object(_))
+    ) {
+        return Err("params must be an array or an object");
+    }
+    if !matches!(
+        call.get("id"),
+        None | Some(Value::String(_) | Value::Number(_) | Value::Null)
+    ) {
+        return Err("id must be a string, a number or null");
+    }
+    Ok(())
+}
+
+/// 按 key 把批量请求中的调用分组，组的顺序是第一次出现的顺序，组内是原来的下标
+pub fn split<K: PartialEq>(
+    indexes: impl IntoIterator<Item = usize>,
+    key: impl Fn(usize) -> K,
+) -> Vec<(K, Vec<usize>)> {
+    let mut groups: Vec<(K, Vec<usize>)> = Vec::new();
+    for i in indexes {
+        let k = key(i);
+        match groups.iter_mut().find(|(g, _)| *g == k) {
+            Some((_, indexes)) => indexes.push(i),
+            None => groups.push((k, vec![i])),
+        }
+    }
+    groups
+}
+
+/// 分组请求的结果，成功时是节点返回的内容，失败时是给组内每个调用的错误信息
+pub type Reply = Result<Value, String>;
+
+pub fn responses(
+    calls: &[Value],
+    replies: Vec<(Vec<usize>, Reply)>,
+    rejected: Vec<(usize, Value)>,
+) -> Vec<Option<Value>> {
+    let mut responses: Vec<Option<Value>> = vec![None; calls.len()];
+    for (i, response) in rejected {
+        responses[i] = Some(response);
+    }
+    for (indexes, reply) in replies {
+        let mut by_id: HashMap<String, VecDeque<Value>> = HashMap::new();
+        let message = match reply {
+            Ok(Value::Array(items)) => {
+                for item in items {
+                    let id = item.get("id").map(Value::to_string).unwrap_or_default();
+                    by_id.entry(id).or_default().push_back(item);
+                }
+                "missing response".to_string()
+            }
+            // 整个批量请求的错误，例如节点不支持批量请求
+            Ok(item) => match item.get("error") {
+                Some(error) => error
+                    .get("message")
+                    .and_then(Value::as_str)
+                    .unwrap_or("upstream error")
+                    .to_string(),
+                None => "invalid response".to_string(),
+            },
+            Err(message) => message,
+        };
+        for i in indexes {
+            let Some(id) = calls[i].get("id") else {
+                continue;
+            };
+            let response = by_id
+                .get_mut(&id.to_string())
+                .and_then(VecDeque::pop_front)
+                .unwrap_or_else(|| error(id, INTERNAL_ERROR, &message));
+            responses[i] = Some(response);
+        }
+    }
+    responses
+}
+
+/// 去掉通知的位置，得到返回给客户端的批量响应
+pub fn join(responses: Vec<Option<Value>>) -> Vec<Value> {
+    responses.into_iter().flatten().collect()
+}
+
+pub fn error(id: &Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_and_join() {
+        let calls: Vec<Value> = serde_json::from_str(
+            r#"[
+                {"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"},
+                {"jsonrpc":"2.0","id":"a","method":"trace_block","params":["latest"]},
+                {"jsonrpc":"2.0","method":"eth_subscribe"},
+                {"jsonrpc":"2.0","id":3,"method":"eth_chainId"},
+                {"jsonrpc":"2.0","id":4,"method":"trace_transaction"}
+            ]"#,
+        )
+        .unwrap();
+        let method = |call: &Value| call["method"].as_str().unwrap_or_default().to_string();
+        let groups = split(0..calls.len(), |i| method(&calls[i]).starts_with("trace_"));
+        assert_eq!(groups, vec![(false, vec![0, 2, 3]), (true, vec![1, 4])]);
+
+        let reply = |ids: &[Value]| -> Reply {
+            let items = ids
+                .iter()
+                .map(|id| json!({"jsonrpc":"2.0","id":id,"result":id}));
+            Ok(Value::Array(items.collect()))
+        };
+        // 节点返回的顺序和请求不同
+        let replies = vec![
+            (vec![0, 2, 3], reply(&[json!(3), json!(1)])),
+            (vec![1, 4], Err("upstream unavailable".to_string())),
+        ];
+        let joined = join(responses(&calls, replies, vec![]));
+        let ids: Vec<&Value> = joined.iter().map(|r| &r["id"]).collect();
+        assert_eq!(ids, vec![&json!(1), &json!("a"), &json!(3), &json!(4)]);
+        assert_eq!(joined[0]["result"], json!(1));
+        assert_eq!(joined[1]["error"]["code"], json!(INTERNAL_ERROR));
+
+        let replies = vec![(vec![0, 2, 3], reply(&[json!(1)]))];
+        let rejected = vec![(1, error(&json!("a"), METHOD_NOT_ALLOWED, "not allowed"))];
+        let joined = join(responses(&calls[..4], replies, rejected));
+        assert_eq!(joined[1]["error"]["code"], json!(METHOD_NOT_ALLOWED));
+        assert_eq!(joined[2]["error"]["message"], json!("missing response"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let call = |s: &str| validate(&serde_json::from_str(s).unwrap());
+        assert!(call(r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}"#).is_ok());
+        assert!(call(r#"{"jsonrpc":"2.0","method":"eth_call","params":[{}]}"#).is_ok());
+        assert!(call(r#"{"jsonrpc":"1.0","id":1,"method":"eth_chainId"}"#).is_err());
+        assert!(call(r#"{"jsonrpc":"2.0","id":1}"#).is_err());
+        assert!(call(r#"{"jsonrpc":"2.0","id":1,"method":"eth_call","params":"x"}"#).is_err());
+        assert!(call(r#"{"jsonrpc":"2.0","id":[1],"method":"eth_chainId"}"#).is_err());
+        assert!(call("1").is_err());
+    }
+}
@@ -0,0 +1,699 @@
+pub mod batch;
+pub mod pool;
+pub mod result_cache;
+
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Bytes, Full},
+    extract::ConnectInfo,
+    http::{header, header::HeaderName, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use self::pool::Pool;
+use crate::model::{
+    anomaly, app, billing,
+    log_parse::{
+        cache,
+        log::{self, Log},
+        route::{Route, Transport, KEY_PARAMS},
+    },
+    registry::{self, Family, Tier},
+};
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    let timeout = std::env::var("PROXY_TIMEOUT_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TIMEOUT_MS);
+    reqwest::Client::builder()
+        .timeout(Duration::from_millis(timeout))
+        .build()
+        .unwrap_or_default()
+});
+
+const DEFAULT_TIMEOUT_MS: u64 = 10000;
+const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+/// 幂等的请求最多尝试的节点数
+const MAX_ATTEMPTS: usize = 3;
+/// 代理产生的日志的节点名
+pub const PROXY_NODE: &str = "proxy";
+
+/// 方法名中包含这些词的调用会改变链上状态，失败后不能换节点重试
+const WRITE_WORDS: [&str; 6] = [
+    "send",
+    "submit",
+    "broadcast",
+    "execute",
+    "addinvoke",
+    "adddeclare",
+];
+
+/// 把 `/ethereum/<key>` 这样的请求转发到这条链这个网络、应用所选节点类型的节点池，
+/// 链的规则匹配的方法转发到专门的节点池，作为 api 的 fallback 挂在路由上。
+/// 幂等的请求失败后换一个节点重试，不会再变的结果直接从缓存返回。
+/// 不属于任何应用的 api key 返回 401，被限流的返回 429，余额用完的返回 402，
+/// 除了 401 每个请求都产生一条日志进入用量统计。
+/// websocket 仍然由 nginx 转发
+pub async fn handle(
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let request_uri = uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or(uri.path());
+    let Some(route) = Route::parse(request_uri) else {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    };
+    // 只转发属于某个应用的 api key，其它请求不记日志
+    let Some(key) = app::key(&route.api_key) else {
+        return (StatusCode::UNAUTHORIZED, "invalid api key").into_response();
+    };
+    let start = Instant::now();
+    let forwarded = if anomaly::is_throttled(&route.api_key) {
+        Forwarded::refused(StatusCode::TOO_MANY_REQUESTS, "api key is throttled")
+    } else if billing::is_exhausted(&key.account) {
+        Forwarded::refused(StatusCode::PAYMENT_REQUIRED, "credits exhausted")
+    } else if route.transport == Transport::WebSocket {
+        Forwarded::refused(StatusCode::NOT_IMPLEMENTED, "websocket is not proxied")
+    } else {
+        forward(&route, key.tier, &method, &uri, &headers, body.clone()).await
+    };
+
+    let log = Log {
+        node: PROXY_NODE.to_string(),
+        msec: Utc::now(),
+        request_length: Some(body.len() as u64),
+        remote_addr: remote.ip().to_string(),
+        remote_port: Some(remote.port()),
+        request: format!("{} {} HTTP/1.1", method, request_uri),
+        request_uri: request_uri.to_string(),
+        status: forwarded.response.status().as_u16(),
+        http_user_agent: header_value(&headers, header::USER_AGENT),
+        http_x_forwarded_for: header_value(&headers, "x-forwarded-for"),
+        http_host: header_value(&headers, header::HOST),
+        request_time: Some(start.elapsed().as_secs_f64()),
+        request_method: method.as_str().parse().unwrap_or_default(),
+        server_protocol: "HTTP/1.1".to_string(),
+        tier: Some(key.tier),
+        ..Default::default()
+    };
+    // 拒绝的调用单独记一条 4xx 的日志，计入请求数但不计费
+    let answered = !forwarded.rejected.is_empty() || !forwarded.hits.is_empty();
+    if !forwarded.rejected.is_empty() {
+        cache::push(Log {
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            rpc_methods: forwarded.rejected,
+            ..log.clone()
+        });
+    }
+    // 缓存返回的调用也单独记一条，没有经过节点
+    if !forwarded.hits.is_empty() {
+        cache::push(Log {
+            status: StatusCode::OK.as_u16(),
+            upstream_cache_status: Some("HIT".to_string()),
+            rpc_methods: forwarded.hits,
+            ..log.clone()
+        });
+    }
+    if answered && forwarded.methods.is_empty() {
+        return forwarded.response;
+    }
+    let upstreams = forwarded.upstreams;
+    cache::push(Log {
+        // 和 nginx 一样用逗号分隔多个节点
+        upstream: (!upstreams.is_empty()).then(|| {
+            let urls: Vec<&str> = upstreams.iter().map(|(url, _)| url.as_str()).collect();
+            urls.join(", ")
+        }),
+        upstream_response_time: upstreams.iter().map(|(_, time)| *time).reduce(f64::max),
+        upstream_cache_status: forwarded.cache_status.map(String::from),
+        rpc_methods: forwarded.methods,
+        ..log
+    });
+    forwarded.response
+}
+
+/// 请求过的节点和耗时，批量请求拆开转发时有多个
+type Upstreams = Vec<(String, f64)>;
+
+/// 节点的响应，拆开的批量请求需要先解析再合并
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Reply {
+    fn error(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            body: Bytes::from(message.to_string()),
+        }
+    }
+
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Full::from(self.body)).into_response();
+        *response.status_mut() = self.status;
+        response.headers_mut().extend(self.headers);
+        response
+    }
+}
+
+/// 转发的结果和用量统计需要的方法名
+struct Forwarded {
+    response: Response,
+    upstreams: Upstreams,
+    /// 转发给节点的调用的方法名
+    methods: Vec<String>,
+    /// 被拒绝的调用的方法名，没有方法名的调用是空字符串
+    rejected: Vec<String>,
+    /// 从缓存返回的调用的方法名
+    hits: Vec<String>,
+    /// 转发的调用中有可以缓存但没有命中的时是 MISS
+    cache_status: Option<&'static str>,
+}
+
+impl Forwarded {
+    /// 没有转发给节点，整个请求直接拒绝
+    fn refused(status: StatusCode, message: &'static str) -> Self {
+        Self {
+            response: (status, message).into_response(),
+            upstreams: vec![],
+            methods: vec![],
+            rejected: vec![],
+            hits: vec![],
+            cache_status: None,
+        }
+    }
+}
+
+/// 按方法选择节点池，JSON-RPC 的链拒绝注册表中屏蔽的方法，批量请求逐个检查
+async fn forward(
+    route: &Route,
+    tier: Tier,
+    method: &Method,
+    uri: &Uri,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Forwarded {
+    let path = upstream_path(uri, &route.api_key);
+    let chain = registry::get().chain(&route.chain);
+    let is_rpc = chain.is_some_and(|c| c.family != Family::AptosRest);
+    let call = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Array(calls)) if *method == Method::POST && is_rpc => {
+            return forward_batch(route, tier, method, &path, headers, body, calls).await;
+        }
+        Ok(call) if *method == Method::POST => call,
+        _ => Value::Null,
+    };
+    let rpc_method = call.get("method").and_then(Value::as_str);
+    if let (Some(chain), Some(name)) = (chain, rpc_method) {
+        if is_rpc && chain.is_blocked(name) {
+            let id = call.get("id").unwrap_or(&Value::Null);
+            let error = batch::error(id, batch::METHOD_NOT_ALLOWED, &not_allowed(name));
+            return Forwarded {
+                response: (StatusCode::FORBIDDEN, Json(error)).into_response(),
+                upstreams: vec![],
+                methods: vec![],
+                rejected: vec![name.to_string()],
+                hits: vec![],
+                cache_status: None,
+            };
+        }
+    }
+    let key = chain
+        .filter(|_| is_rpc)
+        .and_then(|chain| result_cache::key(chain, &route.network, &call));
+    if let (Some(key), Some(name)) = (&key, rpc_method) {
+        if let Some(result) = result_cache::get(key) {
+            return Forwarded {
+                response: Json(result_cache::response(&call, result)).into_response(),
+                upstreams: vec![],
+                methods: vec![],
+                rejected: vec![],
+                hits: vec![name.to_string()],
+                cache_status: None,
+            };
+        }
+    }
+    let pool = pool::select(&route.chain, &route.network, tier, rpc_method);
+    let methods = log::rpc_methods(&String::from_utf8_lossy(&body));
+    let (reply, upstreams) = send(pool, method, &path, headers, body).await;
+    if let Some(key) = &key {
+        if reply.status.is_success() {
+            if let Ok(response) = serde_json::from_slice::<Value>(&reply.body) {
+                store(key, &response);
+            }
+        }
+    }
+    Forwarded {
+        response: reply.into_response(),
+        upstreams,
+        methods,
+        rejected: vec![],
+        hits: vec![],
+        cache_status: key.map(|_| "MISS"),
+    }
+}
+
+/// 缓存节点对一个调用的成功响应
+fn store(key: &result_cache::Key, response: &Value) {
+    if response.get("error").is_none() {
+        if let Some(result) = response.get("result") {
+            result_cache::put(key, result);
+        }
+    }
+}
+
+/// 批量请求：检查数量上限，逐个校验和检查屏蔽的方法，拒绝的调用直接返回错误，
+/// 命中缓存的调用直接返回结果。其余的调用需要不同的节点池时拆开转发，再按原来的顺序合并
+async fn forward_batch(
+    route: &Route,
+    tier: Tier,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+    calls: Vec<Value>,
+) -> Forwarded {
+    let name = |call: &Value| call.get("method").and_then(Value::as_str).map(String::from);
+    let reject = |status: StatusCode, message: &str| Forwarded {
+        response: (
+            status,
+            Json(batch::error(&Value::Null, batch::INVALID_REQUEST, message)),
+        )
+            .into_response(),
+        upstreams: vec![],
+        methods: vec![],
+        rejected: calls.iter().map(|c| name(c).unwrap_or_default()).collect(),
+        hits: vec![],
+        cache_status: None,
+    };
+    if calls.is_empty() {
+        return reject(StatusCode::BAD_REQUEST, "empty batch");
+    }
+    let max = max_batch_size();
+    if calls.len() > max {
+        let message = format!(
+            "batch of {} calls exceeds the limit of {}",
+            calls.len(),
+            max
+        );
+        return reject(StatusCode::PAYLOAD_TOO_LARGE, &message);
+    }
+
+    let chain = registry::get().chain(&route.chain);
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut rejected_methods = Vec::new();
+    let mut hits = Vec::new();
+    let mut misses = Vec::new();
+    for (i, call) in calls.iter().enumerate() {
+        let id = match call.get("id") {
+            Some(id @ (Value::String(_) | Value::Number(_))) => id.clone(),
+            _ => Value::Null,
+        };
+        let error = match (batch::validate(call), name(call)) {
+            (Err(message), _) => Some((batch::INVALID_REQUEST, message.to_string())),
+            (Ok(()), Some(m)) if chain.is_some_and(|c| c.is_blocked(&m)) => {
+                Some((batch::METHOD_NOT_ALLOWED, not_allowed(&m)))
+            }
+            _ => None,
+        };
+        match error {
+            Some((code, message)) => {
+                // 被拒绝的通知也没有响应，无效的调用总是返回错误
+                if call.get("id").is_some() || code == batch::INVALID_REQUEST {
+                    rejected.push((i, batch::error(&id, code, &message)));
+                }
+                rejected_methods.push(name(call).unwrap_or_default());
+            }
+            None => {
+                let key = chain.and_then(|c| result_cache::key(c, &route.network, call));
+                match key.as_ref().and_then(result_cache::get) {
+                    Some(result) => {
+                        if call.get("id").is_some() {
+                            rejected.push((i, result_cache::response(call, result)));
+                        }
+                        hits.push(name(call).unwrap_or_default());
+                    }
+                    None => {
+                        misses.extend(key.map(|key| (i, key)));
+                        accepted.push(i);
+                    }
+                }
+            }
+        }
+    }
+    let cache_status = (!misses.is_empty()).then_some("MISS");
+    let methods: Vec<String> = accepted.iter().filter_map(|&i| name(&calls[i])).collect();
+
+    let select = |i: usize| {
+        let method = calls[i].get("method").and_then(Value::as_str);
+        pool::select(&route.chain, &route.network, tier, method)
+    };
+    // 按节点池分组，同一个请求的链和网络相同，节点池的类型就能区分
+    let groups = batch::split(accepted, |i| select(i).map(|p| p.kind.clone()));
+    // 不需要拆开时原样转发，节点返回的顺序可能和请求不同，同样按 id 排好
+    if groups.len() == 1 && rejected_methods.is_empty() && hits.is_empty() {
+        let (indexes, pool) = (groups[0].1.clone(), select(groups[0].1[0]));
+        let (reply, upstreams) = send(pool, method, path, headers, body).await;
+        let response = match serde_json::from_slice::<Value>(&reply.body) {
+            Ok(value @ Value::Array(_)) if reply.status.is_success() => {
+                let responses = batch::responses(&calls, vec![(indexes, Ok(value))], vec![]);
+                store_batch(&misses, &responses);
+                let joined = batch::join(responses);
+                (StatusCode::OK, Json(joined)).into_response()
+            }
+            _ => reply.into_response(),
+        };
+        return Forwarded {
+            response,
+            upstreams,
+            methods,
+            rejected: rejected_methods,
+            hits,
+            cache_status,
+        };
+    }
+
+    let tasks: Vec<_> = groups
+        .into_iter()
+        .map(|(_, indexes)| {
+            let pool = select(indexes[0]);
+            let group: Vec<&Value> = indexes.iter().map(|&i| &calls[i]).collect();
+            let body = Bytes::from(serde_json::to_vec(&group).unwrap_or_default());
+            let (method, path, headers) = (method.clone(), path.to_string(), headers.clone());
+            let task =
+                tokio::spawn(async move { send(pool, &method, &path, &headers, body).await });
+            (indexes, task)
+        })
+        .collect();
+    let mut replies = Vec::new();
+    let mut upstreams = Vec::new();
+    for (indexes, task) in tasks {
+        let reply = match task.await {
+            Ok((reply, used)) => {
+                upstreams.extend(used);
+                match serde_json::from_slice::<Value>(&reply.body) {
+                    Ok(value) if reply.status.is_success() => Ok(value),
+                    _ => Err(format!("upstream returned {}", reply.status.as_u16())),
+                }
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        replies.push((indexes, reply));
+    }
+    let responses = batch::responses(&calls, replies, rejected);
+    store_batch(&misses, &responses);
+    let joined = batch::join(responses);
+    Forwarded {
+        response: (StatusCode::OK, Json(joined)).into_response(),
+        upstreams,
+        methods,
+        rejected: rejected_methods,
+        hits,
+        cache_status,
+    }
+}
+
+/// 缓存批量请求中没有命中的调用的响应，按调用的下标对应
+fn store_batch(misses: &[(usize, result_cache::Key)], responses: &[Option<Value>]) {
+    for (i, key) in misses {
+        if let Some(response) = &responses[*i] {
+            store(key, response);
+        }
+    }
+}
+
+fn not_allowed(method: &str) -> String {
+    format!("method {} is not allowed", method)
+}
+
+/// 一个批量请求最多包含的调用数
+fn max_batch_size() -> usize {
+    std::env::var("PROXY_MAX_BATCH_SIZE")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BATCH_SIZE)
+}
+
+/// 在节点池中选择节点转发，幂等的请求失败后换节点重试
+async fn send(
+    pool: Option<&Pool>,
+    method: &Method,
+    path: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> (Reply, Upstreams) {
+    let Some(pool) = pool else {
+        return (
+            Reply::error(StatusCode::SERVICE_UNAVAILABLE, "no upstream"),
+            vec![],
+        );
+    };
+    let attempts = if is_idempotent(method, &body) {
+        MAX_ATTEMPTS.min(pool.len())
+    } else {
+        1
+    };
+    let mut tried = Vec::new();
+    let mut last = None;
+    while tried.len() < attempts {
+        let Some(member) = pool.pick(&tried) else {
+            break;
+        };
+        tried.push(member);
+        let url = format!("{}{}", pool.url(member).trim_end_matches('/'), path);
+        let request = CLIENT
+            .request(method.clone(), &url)
+            .headers(end_to_end(headers, &REQUEST_SKIP))
+            .body(body.clone());
+        let start = Instant::now();
+        let result = request.send().await;
+        let upstream = (pool.url(member).to_string(), start.elapsed().as_secs_f64());
+        match result {
+            Ok(response) if !response.status().is_server_error() => {
+                let reply = read(response).await;
+                pool.report(
+                    member,
+                    reply.status != StatusCode::BAD_GATEWAY,
+                    start.elapsed(),
+                );
+                return (reply, vec![upstream]);
+            }
+            Ok(response) => {
+                pool.report(member, false, start.elapsed());
+                last = Some((read(response).await, upstream));
+            }
+            Err(e) => {
+                pool.report(member, false, start.elapsed());
+                tracing::warn!("proxy to {} failed: {}", url, e);
+                let status = if e.is_timeout() {
+                    StatusCode::GATEWAY_TIMEOUT
+                } else {
+                    StatusCode::BAD_GATEWAY
+                };
+                last = Some((Reply::error(status, "upstream unavailable"), upstream));
+            }
+        }
+    }
+    match last {
+        Some((reply, upstream)) => (reply, vec![upstream]),
+        None => (
+            Reply::error(StatusCode::SERVICE_UNAVAILABLE, "no upstream"),
+            vec![],
+        ),
+    }
+}
+
+async fn read(upstream: reqwest::Response) -> Reply {
+    let status = upstream.status();
+    let headers = end_to_end(upstream.headers(), &[header::CONTENT_LENGTH]);
+    match upstream.bytes().await {
+        Ok(body) => Reply {
+            status,
+            headers,
+            body,
+        },
+        Err(_) => Reply::error(StatusCode::BAD_GATEWAY, "upstream unavailable"),
+    }
+}
+
+/// 去掉链的前缀和 api key 之后转发给节点的路径，`/aptos/<key>/v1/accounts?apikey=x&a=1`
+/// 转发为 `/v1/accounts?a=1`
+fn upstream_path(uri: &Uri, api_key: &str) -> String {
+    let mut segments = uri.path().trim_start_matches('/').split('/').skip(1);
+    let rest: Vec<&str> = match segments.next() {
+        Some(segment) if segment == api_key => segments.collect(),
+        Some(segment) => std::iter::once(segment).chain(segments).collect(),
+        None => Vec::new(),
+    };
+    let mut path = rest.iter().map(|s| format!("/{}", s)).collect::<String>();
+    let query: Vec<&str> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            !KEY_PARAMS.contains(&name.to_lowercase().as_str())
+        })
+        .collect();
+    if !query.is_empty() {
+        path.push('?');
+        path.push_str(&query.join("&"));
+    }
+    path
+}
+
+/// 逐跳的头，只在一个连接上有效，不转发
+const HOP_BY_HOP: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// 转发给节点时不带的头。Host 和 Content-Length 由 reqwest 按节点地址和请求体重新设置；
+/// 批量请求和结果缓存要解析节点的响应，所以不转发 Accept-Encoding，让节点返回未压缩的内容
+const REQUEST_SKIP: [HeaderName; 3] = [
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::ACCEPT_ENCODING,
+];
+
+/// 去掉逐跳的头、Connection 中列出的头和 skip 中的头，其余原样转发
+fn end_to_end(headers: &HeaderMap, skip: &[HeaderName]) -> HeaderMap {
+    let listed: Vec<String> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    let mut forwarded = HeaderMap::new();
+    for (name, value) in headers {
+        if HOP_BY_HOP.contains(name)
+            || skip.contains(name)
+            || listed.iter().any(|l| l == name.as_str())
+        {
+            continue;
+        }
+        forwarded.append(name.clone(), value.clone());
+    }
+    forwarded
+}
+
+/// GET 和 HEAD，以及只包含读方法的 JSON-RPC 请求可以重试
+fn is_idempotent(method: &Method, body: &[u8]) -> bool {
+    if method == Method::GET || method == Method::HEAD {
+        return true;
+    }
+    if method != Method::POST {
+        return false;
+    }
+    let methods = log::rpc_methods(&String::from_utf8_lossy(body));
+    !methods.is_empty()
+        && methods.iter().all(|m| {
+            let m = m.to_lowercase();
+            !WRITE_WORDS.iter().any(|word| m.contains(word))
+        })
+}
+
+fn header_value(headers: &HeaderMap, name: impl header::AsHeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v: &HeaderValue| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upstream_path() {
+        let uri: Uri = "/ethereum/0123abcd".parse().unwrap();
+        assert_eq!(upstream_path(&uri, "0123abcd"), "");
+        let uri: Uri = "/aptos/0123abcd/v1/accounts?a=1".parse().unwrap();
+        assert_eq!(upstream_path(&uri, "0123abcd"), "/v1/accounts?a=1");
+        // 转发的路径用的是 Route::parse 得到的 key
+        let request_uri = "/aptos/v1/accounts?api_key=0123abcd&a=1";
+        let route = Route::parse(request_uri).unwrap();
+        let uri: Uri = request_uri.parse().unwrap();
+        assert_eq!(upstream_path(&uri, &route.api_key), "/v1/accounts?a=1");
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        let call = |method: &str| format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{}"}}"#, method);
+        assert!(is_idempotent(&Method::GET, b""));
+        assert!(is_idempotent(
+            &Method::POST,
+            call("eth_getBalance").as_bytes()
+        ));
+        assert!(!is_idempotent(
+            &Method::POST,
+            call("eth_sendRawTransaction").as_bytes()
+        ));
+        assert!(!is_idempotent(
+            &Method::POST,
+            call("sui_executeTransactionBlock").as_bytes()
+        ));
+        let batch = format!("[{},{}]", call("eth_chainId"), call("eth_sendTransaction"));
+        assert!(!is_idempotent(&Method::POST, batch.as_bytes()));
+        assert!(!is_idempotent(&Method::POST, b"not json"));
+    }
+
+    #[test]
+    fn test_end_to_end() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer x"));
+        headers.insert(header::HOST, HeaderValue::from_static("rpc.example.com"));
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, x-trace"),
+        );
+        headers.insert("x-trace", HeaderValue::from_static("1"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        let forwarded = end_to_end(&headers, &REQUEST_SKIP);
+        let mut names: Vec<&str> = forwarded.keys().map(|name| name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["authorization", "content-type"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.insert(header::RETRY_AFTER, HeaderValue::from_static("5"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("10"));
+        let forwarded = end_to_end(&headers, &[header::CONTENT_LENGTH]);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded[header::RETRY_AFTER], "5");
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::model::{
+    health,
+    registry::{self, Balance, NetworkStatus, Registry, Tier, UpstreamConfig},
+};
+
+static POOLS: Lazy<HashMap<(String, String, PoolKind), Pool>> =
+    Lazy::new(|| pools(registry::get()));
+
+/// 连续失败这么多次后暂时摘除节点
+const MAX_FAILS: u32 = 3;
+/// 摘除的时间
+const EJECT_SECS: u64 = 30;
+/// 延迟的指数移动平均系数
+const LATENCY_ALPHA: f64 = 0.3;
+
+/// 节点类型的节点池，或者按方法选择的专门的节点池
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PoolKind {
+    Tier(Tier),
+    Named(String),
+}
+
+/// 某条链某个网络的节点池
+#[derive(Debug)]
+pub struct Pool {
+    pub chain: String,
+    pub network: String,
+    pub kind: PoolKind,
+    balance: Balance,
+    members: Vec<UpstreamConfig>,
+    state: Mutex<Vec<MemberState>>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct MemberState {
+    /// 平滑加权轮询的当前权重
+    current_weight: i64,
+    fails: u32,
+    ejected_until: Option<Instant>,
+    latency_ms: Option<f64>,
+}
+
+impl Pool {
+    pub fn new(
+        chain: &str,
+        network: &str,
+        kind: PoolKind,
+        balance: Balance,
+        members: Vec<UpstreamConfig>,
+    ) -> Self {
+        let state = vec![MemberState::default(); members.len()];
+        Self {
+            chain: chain.to_string(),
+            network: network.to_string(),
+            kind,
+            balance,
+            members,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn url(&self, member: usize) -> &str {
+        &self.members[member].url
+    }
+
+    /// 选择一个节点，跳过 tried 中已经试过的节点。被摘除或者健康检查失败的节点不参与选择，
+    /// 所有节点都不可用时仍然在没有试过的节点中选择，总比直接失败好
+    pub fn pick(&self, tried: &[usize]) -> Option<usize> {
+        self.pick_with(tried, Instant::now(), |url| {
+            health::upstream_up(&self.chain, &self.network, url)
+        })
+    }
+
+    fn pick_with(
+        &self,
+        tried: &[usize],
+        now: Instant,
+        health: impl Fn(&str) -> Option<bool>,
+    ) -> Option<usize> {
+        let mut state = self.state.lock().ok()?;
+        let untried: Vec<usize> = (0..self.members.len())
+            .filter(|i| !tried.contains(i))
+            .collect();
+        let healthy: Vec<usize> = untried
+            .iter()
+            .copied()
+            .filter(|&i| state[i].ejected_until.is_none_or(|until| until <= now))
+            .filter(|&i| health(&self.members[i].url) != Some(false))
+            .collect();
+        let candidates = if healthy.is_empty() { untried } else { healthy };
+        if candidates.is_empty() {
+            return None;
+        }
+        let picked = match self.balance {
+            Balance::RoundRobin => {
+                let total: i64 = candidates
+                    .iter()
+                    .map(|&i| self.members[i].weight as i64)
+                    .sum();
+                for &i in &candidates {
+                    state[i].current_weight += self.members[i].weight as i64;
+                }
+                let picked = candidates
+                    .iter()
+                    .copied()
+                    .max_by_key(|&i| (state[i].current_weight, std::cmp::Reverse(i)))?;
+                state[picked].current_weight -= total;
+                picked
+            }
+            // 还没有延迟数据的节点先试一次
+            Balance::LeastLatency => candidates.iter().copied().min_by(|&a, &b| {
+                let latency = |i: usize| state[i].latency_ms.unwrap_or(0.0);
+                latency(a).total_cmp(&latency(b))
+            })?,
+        };
+        Some(picked)
+    }
+
+    /// 记录一次请求的结果，超时、连接失败和 5xx 算失败
+    pub fn report(&self, member: usize, ok: bool, latency: Duration) {
+        self.report_at(member, ok, latency, Instant::now());
+    }
+
+    fn report_at(&self, member: usize, ok: bool, latency: Duration, now: Instant) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let state = &mut state[member];
+        if ok {
+            state.fails = 0;
+            let latency = latency.as_secs_f64() * 1000.0;
+            state.latency_ms = Some(match state.latency_ms {
+                Some(avg) => avg + LATENCY_ALPHA * (latency - avg),
+                None => latency,
+            });
+            return;
+        }
+        state.fails += 1;
+        if state.fails >= MAX_FAILS {
+            state.fails = 0;
+            state.ejected_until = Some(now + Duration::from_secs(EJECT_SECS));
+            tracing::warn!(
+                "eject upstream {} of {} {} for {}s",
+                self.members[member].url,
+                self.chain,
+                self.network,
+                EJECT_SECS
+            );
+        }
+    }
+}
+
+/// 注册表中每个没有下线的网络的每种节点和每个专门的节点池，没有配置节点的不建
+fn pools(registry: &Registry) -> HashMap<(String, String, PoolKind), Pool> {
+    let mut pools = HashMap::new();
+    for chain in &registry.chains {
+        for network in &chain.networks {
+            if network.status == NetworkStatus::Retired {
+                continue;
+            }
+            let tiers = [Tier::Full, Tier::Archive]
+                .into_iter()
+                .map(|tier| (PoolKind::Tier(tier), network.upstreams.tier(tier)));
+            let named = network
+                .upstreams
+                .pools
+                .iter()
+                .map(|(name, members)| (PoolKind::Named(name.clone()), members.as_slice()));
+            for (kind, members) in tiers.chain(named) {
+                if members.is_empty() {
+                    continue;
+                }
+                let key = (chain.name.clone(), network.name.clone(), kind.clone());
+                let pool = Pool::new(
+                    &chain.name,
+                    &network.name,
+                    kind,
+                    network.balance,
+                    members.to_vec(),
+                );
+                pools.insert(key, pool);
+            }
+        }
+    }
+    pools
+}
+
+pub fn get(chain: &str, network: &str, tier: Tier) -> Option<&'static Pool> {
+    POOLS.get(&(chain.to_string(), network.to_string(), PoolKind::Tier(tier)))
+}
+
+/// 按方法选择节点池，链的规则匹配这个方法并且网络配置了规则选择的节点池时使用它，
+/// 否则使用应用的节点类型的节点池
+pub fn select(
+    chain: &str,
+    network: &str,
+    tier: Tier,
+    method: Option<&str>,
+) -> Option<&'static Pool> {
+    let named = method
+        .and_then(|method| registry::get().chain(chain)?.rule_pool(method))
+        .and_then(|name| {
+            let kind = PoolKind::Named(name.to_string());
+            POOLS.get(&(chain.to_string(), network.to_string(), kind))
+        });
+    named.or_else(|| get(chain, network, tier))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(url: &str, weight: u32) -> UpstreamConfig {
+        UpstreamConfig {
+            url: url.to_string(),
+            weight,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = Pool::new(
+            "Ethereum",
+            "Mainnet",
+            PoolKind::Tier(Tier::Full),
+            Balance::RoundRobin,
+            vec![member("a", 3), member("b", 1)],
+        );
+        let now = Instant::now();
+        let picks: Vec<usize> = (0..8)
+            .map(|_| pool.pick_with(&[], now, |_| None).unwrap())
+            .collect();
+        assert_eq!(picks.iter().filter(|&&i| i == 0).count(), 6);
+        // 平滑加权轮询不会连续选中同一个节点太多次
+        assert_eq!(&picks[..4], &[0, 0, 1, 0]);
+
+        // 重试时跳过已经试过的节点，健康检查失败的节点不参与选择
+        assert_eq!(pool.pick_with(&[0], now, |_| None), Some(1));
+        assert_eq!(pool.pick_with(&[], now, |url| Some(url != "a")), Some(1));
+        // 所有节点都不健康时仍然选择
+        assert!(pool.pick_with(&[], now, |_| Some(false)).is_some());
+        assert_eq!(pool.pick_with(&[0, 1], now, |_| None), None);
+    }
+
+    #[test]
+    fn test_ejection_and_latency() {
+        let pool = Pool::new(
+            "Ethereum",
+            "Mainnet",
+            PoolKind::Tier(Tier::Full),
+            Balance::LeastLatency,
+            vec![member("a", 1), member("b", 1)],
+        );
+        let now = Instant::now();
+        pool.report_at(0, true, Duration::from_millis(20), now);
+        pool.report_at(1, true, Duration::from_millis(80), now);
+        assert_eq!(pool.pick_with(&[], now, |_| None), Some(0));
+
+        for _ in 0..MAX_FAILS {
+            pool.report_at(0, false, Duration::from_secs(10), now);
+        }
+        assert_eq!(pool.pick_with(&[], now, |_| None), Some(1));
+        let later = now + Duration::from_secs(EJECT_SECS);
+        assert_eq!(pool.pick_with(&[], later, |_| None), Some(0));
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::model::{
+    health,
+    registry::{ChainConfig, Family},
+};
+
+static BUDGET: Lazy<usize> = Lazy::new(|| {
+    std::env::var("PROXY_CACHE_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CACHE_BYTES)
+});
+static CACHE: Lazy<Mutex<Lru>> = Lazy::new(|| Mutex::new(Lru::new(*BUDGET)));
+
+const DEFAULT_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// 调用的结果在什么情况下不会再变
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rule {
+    /// 结果永远不变，例如 chain id
+    Always,
+    /// 第 n 个参数是已经确定的块号
+    BlockParam(usize),
+    /// 按 hash 查询块或交易，结果中这个字段是已经确定的块号
+    BlockField(&'static str),
+}
+
+fn rule(family: Family, method: &str) -> Option<Rule> {
+    let rule = match (family, method) {
+        (Family::EvmJsonRpc, "eth_chainId" | "net_version") => Rule::Always,
+        (
+            Family::EvmJsonRpc,
+            "eth_getBlockByNumber"
+            | "eth_getBlockReceipts"
+            | "eth_getBlockTransactionCountByNumber",
+        ) => Rule::BlockParam(0),
+        (Family::EvmJsonRpc, "eth_getBlockByHash") => Rule::BlockField("number"),
+        (Family::EvmJsonRpc, "eth_getTransactionByHash" | "eth_getTransactionReceipt") => {
+            Rule::BlockField("blockNumber")
+        }
+        (Family::StarknetJsonRpc, "starknet_chainId") => Rule::Always,
+        (Family::SuiJsonRpc, "sui_getChainIdentifier") => Rule::Always,
+        _ => return None,
+    };
+    Some(rule)
+}
+
+/// 可以缓存的调用
+#[derive(Debug, Clone)]
+pub struct Key {
+    key: String,
+    rule: Rule,
+    /// 已经确定的最高块
+    finalized: Option<u64>,
+}
+
+impl Key {
+    /// 节点返回的结果是否可以缓存，null 的结果以后可能会变
+    fn accepts(&self, result: &Value) -> bool {
+        if result.is_null() {
+            return false;
+        }
+        match self.rule {
+            Rule::Always | Rule::BlockParam(_) => true,
+            Rule::BlockField(field) => block_number(&result[field])
+                .zip(self.finalized)
+                .is_some_and(|(number, finalized)| number <= finalized),
+        }
+    }
+}
+
+/// 这个调用可以缓存时返回它的 key，块号参数还没有确定时不缓存。缓存关闭时总是 None
+pub fn key(chain: &ChainConfig, network: &str, call: &Value) -> Option<Key> {
+    if *BUDGET == 0 {
+        return None;
+    }
+    let finalized = chain
+        .finality
+        .zip(health::head(&chain.name, network))
+        .and_then(|(finality, head)| head.checked_sub(finality));
+    key_at(chain.family, &chain.name, network, call, finalized)
+}
+
+fn key_at(
+    family: Family,
+    chain: &str,
+    network: &str,
+    call: &Value,
+    finalized: Option<u64>,
+) -> Option<Key> {
+    let method = call.get("method")?.as_str()?;
+    let rule = rule(family, method)?;
+    let params = call.get("params").unwrap_or(&Value::Null);
+    if let Rule::BlockParam(n) = rule {
+        let number = block_number(params.get(n)?)?;
+        if finalized.is_none_or(|finalized| number > finalized) {
+            return None;
+        }
+    }
+    Some(Key {
+        // 对象参数的键是排好序的，同样的参数序列化的结果相同
+        key: format!("{}/{}/{}/{}", chain, network, method, params),
+        rule,
+        finalized,
+    })
+}
+
+pub fn get(key: &Key) -> Option<Value> {
+    CACHE.lock().ok()?.get(&key.key)
+}
+
+/// 缓存节点返回的结果，结果以后可能会变时什么也不做
+pub fn put(key: &Key, result: &Value) {
+    if !key.accepts(result) {
+        return;
+    }
+    if let Ok(mut cache) = CACHE.lock() {
+        let size = key.key.len() + result.to_string().len();
+        cache.put(key.key.clone(), result.clone(), size);
+    }
+}
+
+/// 用缓存的结果回复这个调用
+pub fn response(call: &Value, result: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": call.get("id").unwrap_or(&Value::Null),
+        "result": result,
+    })
+}
+
+fn block_number(value: &Value) -> Option<u64> {
+    let hex = value.as_str()?.strip_prefix("0x")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+/// 按字节数限制大小的 LRU，超出时淘汰最久没有使用的结果
+#[derive(Debug, Default)]
+struct Lru {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    /// 结果、大小和最近一次使用的时刻
+    entries: HashMap<String, (Value, usize, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Lru {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Value> {
+        let (value, _, used_at) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        self.order.remove(used_at);
+        self.order.insert(self.tick, key.to_string());
+        *used_at = self.tick;
+        Some(value.clone())
+    }
+
+    fn put(&mut self, key: String, value: Value, size: usize) {
+        if size > self.budget {
+            return;
+        }
+        if let Some((_, size, used_at)) = self.entries.remove(&key) {
+            self.used -= size;
+            self.order.remove(&used_at);
+        }
+        self.tick += 1;
+        self.used += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, size, self.tick));
+        while self.used > self.budget {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some((_, size, _)) = self.entries.remove(&oldest) {
+                self.used -= size;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(10);
+        lru.put("a".to_string(), json!(1), 4);
+        lru.put("b".to_string(), json!(2), 4);
+        assert_eq!(lru.get("a"), Some(json!(1)));
+        // 超出预算时淘汰最久没有使用的 b
+        lru.put("c".to_string(), json!(3), 4);
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.get("a"), Some(json!(1)));
+        assert_eq!(lru.used, 8);
+        // 比整个预算还大的结果不缓存
+        lru.put("d".to_string(), json!(4), 11);
+        assert_eq!(lru.get("d"), None);
+        assert_eq!(lru.get("c"), Some(json!(3)));
+    }
+
+    #[test]
+    fn test_key() {
+        let key = |call: &str, finalized| {
+            let call: Value = serde_json::from_str(call).unwrap();
+            key_at(Family::EvmJsonRpc, "Ethereum", "Mainnet", &call, finalized)
+        };
+        let chain_id = key(r#"{"jsonrpc":"2.0","id":1,"method":"eth_chainId"}"#, None).unwrap();
+        assert!(chain_id.accepts(&json!("0x1")));
+        assert!(key(
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}"#,
+            Some(100)
+        )
+        .is_none());
+
+        let by_number = |number: &str| {
+            format!(
+                r#"{{"method":"eth_getBlockByNumber","params":["{}",false]}}"#,
+                number
+            )
+        };
+        assert!(key(&by_number("0x64"), Some(100)).is_some());
+        assert!(key(&by_number("0x65"), Some(100)).is_none());
+        assert!(key(&by_number("latest"), Some(100)).is_none());
+        assert!(key(&by_number("0x1"), None).is_none());
+
+        let receipt = key(
+            r#"{"method":"eth_getTransactionReceipt","params":["0xab"]}"#,
+            Some(100),
+        )
+        .unwrap();
+        assert!(receipt.accepts(&json!({"blockNumber": "0x64"})));
+        assert!(!receipt.accepts(&json!({"blockNumber": "0x65"})));
+        assert!(!receipt.accepts(&Value::Null));
+    }
+}